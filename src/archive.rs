@@ -0,0 +1,129 @@
+//! Local archive of sent mail.  
+//! Every delivered message is stored exactly as it was sent, either as a file in a Maildir  
+//! or appended to an mbox (using mboxrd quoting, so the original can always be recovered).  
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    process,
+};
+
+use chrono::Utc;
+use lettre::address::Envelope;
+
+use crate::{
+    config::{ArchiveFormat, ConfigManager},
+    crypto, queue, run,
+};
+
+/// Store `raw` in the configured sent archive, does nothing if there is none
+pub fn store(cf: &ConfigManager, envelope: &Envelope, raw: &[u8]) -> anyhow::Result<()> {
+    let Some(archive) = &cf.config.sent_archive else {
+        return Ok(());
+    };
+
+    match archive.format {
+        ArchiveFormat::Maildir => store_maildir(&archive.path, raw),
+        ArchiveFormat::Mbox => store_mbox(&archive.path, envelope, raw),
+    }
+    .map_err(|e| anyhow::anyhow!("failed to archive to '{}': {e}", archive.path.display()))
+}
+
+/// The host name, as part of a Maildir file name
+fn hostname() -> String {
+    run::hostname()
+        // '/' and ':' have a special meaning in Maildir file names
+        .replace('/', "\\057")
+        .replace(':', "\\072")
+}
+
+/// Write the message to `tmp/` and move it to `cur/`, flagged as seen
+fn store_maildir(dir: &Path, raw: &[u8]) -> anyhow::Result<()> {
+    for sub in ["cur", "new", "tmp"] {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+
+    let name = format!(
+        "{}.P{}R{}.{}",
+        queue::now(),
+        process::id(),
+        crypto::random_hex(8),
+        hostname()
+    );
+
+    let tmp = dir.join("tmp").join(&name);
+    fs::write(&tmp, raw)?;
+    fs::rename(&tmp, dir.join("cur").join(format!("{name}:2,S")))?;
+
+    Ok(())
+}
+
+/// Quote `From ` lines mboxrd style: every line matching `>*From ` gets one more '>'
+fn quote_from_lines(raw: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(raw.len());
+
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let unquoted = line.iter().position(|&b| b != b'>').map_or(&[][..], |i| &line[i..]);
+        if unquoted.starts_with(b"From ") {
+            quoted.push(b'>');
+        }
+        quoted.extend_from_slice(line);
+    }
+
+    quoted
+}
+
+/// Append the message to the mbox file, preceded by a `From_` line with the envelope sender
+fn store_mbox(path: &Path, envelope: &Envelope, raw: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let sender = envelope
+        .from()
+        .map(|from| from.to_string())
+        .unwrap_or("MAILER-DAEMON".to_string());
+
+    let mut entry = format!("From {sender} {}\n", Utc::now().format("%a %b %e %H:%M:%S %Y")).into_bytes();
+    entry.extend(quote_from_lines(raw));
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+    // Messages are separated by an empty line
+    entry.push(b'\n');
+
+    // A single write, so concurrent mailr processes don't interleave messages
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&entry)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_lines_get_one_more_quote() {
+        let raw = b"Subject: test\r\n\r\nFrom here\r\n>From there\r\n>>From everywhere\r\nnot From here\r\n";
+
+        assert_eq!(
+            quote_from_lines(raw),
+            b"Subject: test\r\n\r\n>From here\r\n>>From there\r\n>>>From everywhere\r\nnot From here\r\n"
+        );
+    }
+
+    #[test]
+    fn only_from_with_a_space_is_quoted() {
+        let raw = b"From: me@example.com\r\n\r\n>From:\r\nFromage\r\n>\r\n";
+
+        assert_eq!(quote_from_lines(raw), raw);
+    }
+
+    #[test]
+    fn first_line_and_last_line_without_newline() {
+        assert_eq!(quote_from_lines(b"From the start\nto the end"), b">From the start\nto the end");
+        assert_eq!(quote_from_lines(b"body\nFrom the end"), b"body\n>From the end");
+    }
+}
@@ -0,0 +1,554 @@
+//! Sending many messages over pooled SMTP connections, for `mailr merge`, `mailr serve` and the outbox.  
+//! The `[batch]` section limits the messages per minute and the recipients per transaction,  
+//! a message is sent again over a new connection when the relay closes it with a 421 reply.  
+//! With the `async` feature, messages go through lettre's async transport on Tokio, `concurrency` at a time.  
+//! `Batch` is then sent on the caller's runtime, `BlockingBatch` brings a runtime of its own for the CLI commands.  
+//! Without it the same `Batch` is driven over the blocking transport, one message after another.  
+
+#[cfg(not(feature = "async"))]
+use std::{
+    pin::pin,
+    task::{Context, Poll, Waker},
+    thread,
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    slice,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async")]
+use futures_util::{stream, StreamExt};
+#[cfg(feature = "async")]
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+#[cfg(not(feature = "async"))]
+use lettre::{SmtpTransport, Transport};
+use lettre::{
+    address::Envelope,
+    transport::smtp::{self, response::Response},
+    Address,
+};
+
+use crate::{
+    config::{BatchSettings, ConfigManager},
+    info, log, queue, warning,
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// The replies to one message, before it is recorded
+#[derive(Default)]
+struct Attempt {
+    accepted: Vec<Address>,
+    response: Option<Response>,
+    /// Every failed transaction, with its recipients
+    failures: Vec<(Vec<Address>, smtp::Error)>,
+}
+
+impl Attempt {
+    fn record(&mut self, to: &[Address], result: Result<Response, smtp::Error>) {
+        match result {
+            Ok(reply) => {
+                self.accepted.extend_from_slice(to);
+                self.response = Some(reply);
+            }
+            Err(err) => self.failures.push((to.to_vec(), err)),
+        }
+    }
+}
+
+/// A recipient the relay refused
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub to: Address,
+    pub reason: String,
+    /// Whether the failure is temporary (a 4xx reply or a lost connection), see `queue::is_retryable`
+    pub retryable: bool,
+}
+
+/// The refused recipients of a message whose failures are of the same kind, see `Delivery::refused`
+pub struct Refused {
+    /// The envelope of the message, with only these recipients
+    pub envelope: Envelope,
+    /// The last of their replies
+    pub reason: String,
+    pub retryable: bool,
+}
+
+/// What happened to one message, nothing of it is written anywhere before `Delivery::record`
+pub struct Delivery {
+    pub accepted: Vec<Address>,
+    /// Recipients the relay refused, with the reason
+    pub rejected: Vec<Rejection>,
+    from: Option<Address>,
+    response: Option<Response>,
+    /// Every failed transaction, with its recipients
+    failures: Vec<(Vec<Address>, smtp::Error)>,
+}
+
+impl Delivery {
+    /// The last reply if every recipient was accepted, the last error otherwise
+    pub fn result(&self) -> Result<&Response, &smtp::Error> {
+        match (self.failures.last(), &self.response) {
+            (Some((_, err)), _) => Err(err),
+            (None, Some(reply)) => Ok(reply),
+            (None, None) => unreachable!("an envelope always has recipients"),
+        }
+    }
+
+    /// Write the history, the archive and the copy in the Sent folder of `raw`.
+    /// Recorded with the recipients that actually got it, and once for every failed transaction with its reply.
+    /// This blocks on files and IMAP: code on Tokio calls it outside the runtime, e.g. with `spawn_blocking`.
+    pub fn record(&self, cf: &ConfigManager, raw: &[u8]) {
+        if let Some(reply) = &self.response {
+            let accepted = Envelope::new(self.from.clone(), self.accepted.clone()).expect("a reply means an accepted recipient");
+            cf.delivered(&accepted, raw, Ok(reply));
+        }
+        for (to, err) in &self.failures {
+            let refused = Envelope::new(self.from.clone(), to.clone()).expect("a transaction has recipients");
+            cf.delivered(&refused, raw, Err(err));
+        }
+    }
+
+    /// The refused recipients that can be tried again later (`retryable`), or those that were refused for good.
+    /// `None` if there are none of that kind.
+    pub fn refused(&self, envelope: &Envelope, retryable: bool) -> Option<Refused> {
+        let rejections: Vec<&Rejection> = self.rejected.iter().filter(|r| r.retryable == retryable).collect();
+        let last = rejections.last()?;
+
+        let to = rejections.iter().map(|rejection| rejection.to.clone()).collect();
+        Some(Refused {
+            envelope: Envelope::new(envelope.from().cloned(), to).expect("there is a refused recipient"),
+            reason: last.reason.clone(),
+            retryable,
+        })
+    }
+}
+
+/// Totals over all messages sent with a `Batch`
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Messages accepted for at least one recipient
+    pub sent: usize,
+    pub failed: usize,
+    pub accepted: usize,
+    pub rejected: Vec<Rejection>,
+    pub reconnects: usize,
+}
+
+impl Summary {
+    /// Log the totals, and every rejected recipient
+    pub fn print(&self) {
+        info(format!(
+            "messages sent: {}, failed: {}; recipients accepted: {}, rejected: {}",
+            self.sent,
+            self.failed,
+            self.accepted,
+            self.rejected.len()
+        ));
+        if self.reconnects > 0 {
+            info(format!("reconnected {} times", self.reconnects));
+        }
+        for rejection in &self.rejected {
+            warning(format!("rejected '{}': {}", rejection.to, rejection.reason));
+        }
+    }
+}
+
+/// Was the connection closed by the relay, e.g. because it sent too many messages over it?
+fn connection_closed(err: &smtp::Error) -> bool {
+    err.status().is_some_and(|code| code.to_string() == "421")
+}
+
+/// Was it a recipient the relay refused (550, 551, 553), rather than the message (e.g. 552, 554)?
+fn mailbox_refused(err: &smtp::Error) -> bool {
+    err.status().is_some_and(|code| ["550", "551", "553"].contains(&code.to_string().as_str()))
+}
+
+/// Sends messages within the limits of the relay, it can be shared between threads
+pub struct Batch<'a> {
+    settings: &'a BatchSettings,
+    #[cfg(feature = "async")]
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    #[cfg(not(feature = "async"))]
+    transport: &'a SmtpTransport,
+    /// When the transactions of the last minute were (or will be) started
+    recent: Mutex<VecDeque<Instant>>,
+    summary: Mutex<Summary>,
+}
+
+impl<'a> Batch<'a> {
+    /// With the `async` feature it has to be called (and dropped) on a Tokio runtime,
+    /// the connection pool runs a task of its own
+    pub fn new(cf: &'a ConfigManager) -> anyhow::Result<Self> {
+        #[cfg(feature = "async")]
+        let transport = cf.async_transport()?;
+        #[cfg(not(feature = "async"))]
+        let transport = cf.transport()?;
+        cf.log_connecting();
+
+        Ok(Self {
+            settings: &cf.config.batch,
+            transport,
+            recent: Mutex::new(VecDeque::new()),
+            summary: Mutex::new(Summary::default()),
+        })
+    }
+
+    /// The totals of everything sent so far
+    pub fn into_summary(self) -> Summary {
+        std::mem::take(&mut *self.summary.lock().unwrap())
+    }
+
+    /// Reserve the next start allowed by `messages_per_minute`, returns how long to wait for it
+    fn reserve(&self) -> Duration {
+        let Some(limit) = self.settings.messages_per_minute.filter(|&limit| limit > 0) else {
+            return Duration::ZERO;
+        };
+        let limit = limit as usize;
+
+        let mut recent = self.recent.lock().unwrap();
+        let now = Instant::now();
+        while recent.front().is_some_and(|start| now.saturating_duration_since(*start) >= MINUTE) {
+            recent.pop_front();
+        }
+
+        let start = match recent.len() >= limit {
+            true => recent[recent.len() - limit] + MINUTE,
+            false => now,
+        };
+        recent.push_back(start);
+
+        start.saturating_duration_since(now)
+    }
+
+    /// Whether to send again after `err`, and how long to wait before that
+    fn reconnect_delay(&self, err: &smtp::Error, attempts: u32) -> Option<Duration> {
+        if !connection_closed(err) || attempts >= self.settings.reconnect_attempts {
+            return None;
+        }
+
+        self.summary.lock().unwrap().reconnects += 1;
+        Some(Duration::from_secs((attempts + 1).into()))
+    }
+
+    /// One SMTP transaction, repeated over a new connection when the relay closes the old one
+    async fn transaction(&self, from: Option<&Address>, to: &[Address], raw: &[u8]) -> Result<Response, smtp::Error> {
+        let envelope = Envelope::new(from.cloned(), to.to_vec()).expect("a transaction has recipients");
+
+        let mut attempts = 0;
+        loop {
+            sleep(self.reserve()).await;
+
+            // The pool drops a connection after an error, so the next attempt gets a new one
+            #[cfg(feature = "async")]
+            let result = self.transport.send_raw(&envelope, raw).await;
+            #[cfg(not(feature = "async"))]
+            let result = self.transport.send_raw(&envelope, raw);
+
+            match result {
+                Err(err) => match self.reconnect_delay(&err, attempts) {
+                    Some(delay) => sleep(delay).await,
+                    None => return Err(err),
+                },
+                result => return result,
+            }
+            attempts += 1;
+        }
+    }
+
+    /// The recipients of `envelope` in groups of at most `recipients_per_message`
+    fn chunks<'e>(&self, envelope: &'e Envelope) -> slice::Chunks<'e, Address> {
+        let size = self.settings.recipients_per_message.unwrap_or(usize::MAX).max(1);
+        envelope.to().chunks(size)
+    }
+
+    async fn attempt(&self, envelope: &Envelope, raw: &[u8]) -> Attempt {
+        let mut attempt = Attempt::default();
+        for chunk in self.chunks(envelope) {
+            match self.transaction(envelope.from(), chunk, raw).await {
+                // One refused recipient fails the whole transaction, find out which ones it was.
+                // Anything else would fail for each of them as well, so the chunk is rejected as a whole
+                Err(err) if mailbox_refused(&err) && chunk.len() > 1 => {
+                    for to in chunk {
+                        let to = slice::from_ref(to);
+                        attempt.record(to, self.transaction(envelope.from(), to, raw).await);
+                    }
+                }
+                result => attempt.record(chunk, result),
+            }
+        }
+        attempt
+    }
+
+    /// Count a sent message in the totals, its history is written by `Delivery::record`
+    fn finish(&self, envelope: &Envelope, attempt: Attempt) -> Delivery {
+        let Attempt {
+            accepted,
+            response,
+            failures,
+        } = attempt;
+
+        let rejected: Vec<Rejection> = failures
+            .iter()
+            .flat_map(|(to, err)| {
+                to.iter().map(|to| Rejection {
+                    to: to.clone(),
+                    reason: err.to_string(),
+                    retryable: queue::is_retryable_smtp(err),
+                })
+            })
+            .collect();
+
+        let mut summary = self.summary.lock().unwrap();
+        match accepted.is_empty() {
+            true => summary.failed += 1,
+            false => summary.sent += 1,
+        }
+        summary.accepted += accepted.len();
+        summary.rejected.extend(rejected.iter().cloned());
+
+        Delivery {
+            accepted,
+            rejected,
+            from: envelope.from().cloned(),
+            response,
+            failures,
+        }
+    }
+
+    /// Send one message to everyone in `envelope`, in as many transactions as `recipients_per_message` needs
+    pub async fn send(&self, envelope: &Envelope, raw: &[u8]) -> Delivery {
+        let attempt = self.attempt(envelope, raw).await;
+        self.finish(envelope, attempt)
+    }
+
+    /// Send every message in `jobs`, `done` is called with the index of each one as it finishes.
+    /// With the `async` feature up to `concurrency` messages are on their way at the same time,
+    /// so they can finish out of order.
+    pub async fn send_all(
+        &self,
+        jobs: &[(Envelope, Vec<u8>)],
+        mut done: impl FnMut(usize, Delivery) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        #[cfg(feature = "async")]
+        {
+            let mut attempts = stream::iter(jobs.iter().enumerate())
+                .map(|(i, (envelope, raw))| async move { (i, self.attempt(envelope, raw).await) })
+                .buffer_unordered(self.settings.concurrency.max(1));
+
+            while let Some((i, attempt)) = attempts.next().await {
+                done(i, self.finish(&jobs[i].0, attempt))?;
+            }
+        }
+        #[cfg(not(feature = "async"))]
+        for (i, (envelope, raw)) in jobs.iter().enumerate() {
+            done(i, self.send(envelope, raw).await)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+#[cfg(not(feature = "async"))]
+async fn sleep(duration: Duration) {
+    thread::sleep(duration);
+}
+
+/// Without the `async` feature nothing in a `Batch` waits for anything but the blocking transport,
+/// so its futures are done the first time they are polled
+#[cfg(not(feature = "async"))]
+fn block_on<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("the blocking transport never waits"),
+    }
+}
+
+/// A `Batch` for the CLI commands, which block until it is done and write the history of every message.
+/// With the `async` feature it runs on a runtime of its own:
+/// code that is already on Tokio uses `Batch` itself, blocking on another runtime from there panics.
+pub struct BlockingBatch<'a> {
+    cf: &'a ConfigManager,
+    /// Only taken when dropped, see `Drop for BlockingBatch`
+    batch: Option<Batch<'a>>,
+    #[cfg(feature = "async")]
+    runtime: tokio::runtime::Runtime,
+}
+
+impl<'a> BlockingBatch<'a> {
+    #[cfg(feature = "async")]
+    pub fn new(cf: &'a ConfigManager) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batch = {
+            let _runtime = runtime.enter();
+            Batch::new(cf)?
+        };
+        Ok(Self {
+            cf,
+            batch: Some(batch),
+            runtime,
+        })
+    }
+
+    #[cfg(not(feature = "async"))]
+    pub fn new(cf: &'a ConfigManager) -> anyhow::Result<Self> {
+        Ok(Self {
+            cf,
+            batch: Some(Batch::new(cf)?),
+        })
+    }
+
+    fn batch(&self) -> &Batch<'a> {
+        self.batch.as_ref().expect("the batch is there until it is dropped")
+    }
+
+    #[cfg(feature = "async")]
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        block_on(future)
+    }
+
+    /// See `Batch::send`
+    pub fn send(&self, envelope: &Envelope, raw: &[u8]) -> Delivery {
+        let delivery = self.block_on(self.batch().send(envelope, raw));
+        delivery.record(self.cf, raw);
+        delivery
+    }
+
+    /// See `Batch::send_all`.
+    /// `done` and the history run on this thread, the messages still on their way wait for them.
+    pub fn send_all(
+        &self,
+        jobs: &[(Envelope, Vec<u8>)],
+        mut done: impl FnMut(usize, Delivery) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.block_on(self.batch().send_all(jobs, |i, delivery| {
+            delivery.record(self.cf, &jobs[i].1);
+            done(i, delivery)
+        }))
+    }
+
+    /// The totals of everything sent so far
+    pub fn into_summary(mut self) -> Summary {
+        #[cfg(feature = "async")]
+        let _runtime = self.runtime.enter();
+        self.batch.take().expect("the batch is there until it is dropped").into_summary()
+    }
+}
+
+impl Drop for BlockingBatch<'_> {
+    fn drop(&mut self) {
+        // The connection pool closes its connections in a task, which needs the runtime
+        #[cfg(feature = "async")]
+        let _runtime = self.runtime.enter();
+        self.batch.take();
+    }
+}
+
+/// A `[12/300]` counter, redrawn in place on a terminal (see `log::progress`) and logged line by line otherwise.
+/// With `--output json` it stays quiet, every message has a `sent` or `failed` event already.
+pub struct Progress {
+    total: usize,
+    done: usize,
+    in_place: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: 0,
+            in_place: log::progress_in_place(),
+        }
+    }
+
+    /// Count one more message, `detail` says what happened to it
+    pub fn step(&mut self, detail: &str) {
+        self.done += 1;
+        let line = format!("[{}/{}] {detail}", self.done, self.total);
+
+        match self.in_place {
+            true => log::progress(&line),
+            false if !log::is_json() => info(line),
+            false => {}
+        }
+    }
+
+    /// Clear the counter, so a warning or the summary starts on an empty line
+    pub fn clear(&self) {
+        if self.in_place && self.done > 0 {
+            log::clear_progress();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config, fake_relay};
+
+    /// A relay that refuses bad@ for good, asks to try later@ again later and accepts everyone else
+    fn relay() -> u16 {
+        let (port, _) = fake_relay(|command| match command {
+            _ if command.contains("BAD@") => "550 5.1.1 no such user",
+            _ if command.contains("LATER@") => "450 4.2.1 try again later",
+            _ => "250 ok",
+        });
+        port
+    }
+
+    fn address(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn refused_recipients_are_split_by_reply_class() {
+        let cf = config(relay());
+        let batch = BlockingBatch::new(&cf).unwrap();
+        let to = ["a@example.com", "bad@example.com", "later@example.com"].map(address);
+        let envelope = Envelope::new(Some(address("me@example.com")), to.to_vec()).unwrap();
+
+        let delivery = batch.send(&envelope, b"Subject: test\r\n\r\nhello\r\n");
+
+        assert!(delivery.result().is_err());
+        assert_eq!(delivery.accepted, [address("a@example.com")]);
+
+        let retry = delivery.refused(&envelope, true).expect("later@ can be tried again");
+        assert_eq!(retry.envelope.to(), [address("later@example.com")]);
+        assert_eq!(retry.envelope.from(), envelope.from());
+        assert!(retry.retryable);
+        assert!(retry.reason.contains("try again later"), "{}", retry.reason);
+
+        let failed = delivery.refused(&envelope, false).expect("bad@ is refused for good");
+        assert_eq!(failed.envelope.to(), [address("bad@example.com")]);
+        assert!(!failed.retryable);
+        assert!(failed.reason.contains("no such user"), "{}", failed.reason);
+
+        let summary = batch.into_summary();
+        assert_eq!((summary.sent, summary.failed, summary.accepted, summary.rejected.len()), (1, 0, 1, 2));
+    }
+
+    #[test]
+    fn nothing_is_refused_when_everyone_accepts() {
+        let cf = config(relay());
+        let batch = BlockingBatch::new(&cf).unwrap();
+        let envelope = Envelope::new(None, vec![address("a@example.com"), address("b@example.com")]).unwrap();
+
+        let delivery = batch.send(&envelope, b"Subject: test\r\n\r\nhello\r\n");
+
+        assert!(delivery.result().is_ok());
+        assert!(delivery.refused(&envelope, true).is_none());
+        assert!(delivery.refused(&envelope, false).is_none());
+    }
+}
@@ -0,0 +1,598 @@
+//! The `mailr` command line, parses the arguments and runs the commands.  
+
+// TODO: fix hint text after interactive mode end
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+
+use clap::{error::ErrorKind, parser::ValueSource, ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use colored::Colorize;
+use config::ConfigManager;
+use contact_formats::{ContactFormat, VcardVersion};
+use contacts::{AddressBook, Contact, RecipientCompleter};
+use inquire::validator::Validation;
+
+use crate::{
+    config, contact_formats, contacts, exit, history, http, inbox,
+    log::{self, error, hint, info, warning},
+    merge, queue, reply, run, schedule, send_or_queue, serve, templates, transcript, watch, Args,
+};
+
+#[derive(Parser, Debug)]
+#[clap(override_usage(concat!(env!("CARGO_PKG_NAME"), " [--configure] [--queue | --send-at <TIME> | --delay <DURATION>] --to <EMAIL> --subject <SUBJECT> --msg <MESSAGE BODY>\n       ", env!("CARGO_PKG_NAME"), " <COMMAND>")))]
+#[command(subcommand_negates_reqs = true, after_help(exit::EXIT_CODES))]
+pub struct Cli {
+    #[arg(long, global = true, value_enum, default_value_t = log::Format::Text, help("how to write messages, json writes one event per line"))]
+    output: log::Format,
+    #[arg(short, long, global = true, conflicts_with("verbose"), help("only print warnings and errors"))]
+    quiet: bool,
+    #[arg(short, long, global = true, action(ArgAction::Count), help("print more details, -vv also prints the SMTP conversation"))]
+    verbose: u8,
+    #[arg(long, global = true, value_name("FILE"), help("also append all messages to a file, without colors"))]
+    log_file: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a message (the same as passing the arguments without a command)
+    Send(Box<Args>),
+    /// List recent messages in the inbox (or another folder) over IMAP
+    Inbox(inbox::InboxArgs),
+    /// Print a received message
+    Read(inbox::ReadArgs),
+    /// Reply to a received message
+    Reply(reply::ReplyArgs),
+    /// Forward a received message
+    Forward(reply::ForwardArgs),
+    /// Search the history of sent mail
+    History(history::HistoryArgs),
+    /// Manage the address book
+    Contacts {
+        #[command(subcommand)]
+        action: ContactsAction,
+    },
+    /// List or show the message templates
+    Templates {
+        #[command(subcommand)]
+        action: TemplatesAction,
+    },
+    /// Inspect and retry messages in the outbox
+    Queue {
+        #[command(subcommand)]
+        action: QueueAction,
+    },
+    /// List or cancel messages scheduled with --send-at or --delay
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Send a personalized message to every row of a CSV or JSON file
+    Merge(merge::MergeArgs),
+    /// Run a command and mail its exit status and output, e.g. from cron
+    Run(run::RunArgs),
+    /// Follow a log file and mail the lines matching a pattern
+    Watch(watch::WatchArgs),
+    /// Accept mail from local applications over SMTP and relay it with the configured login
+    Serve(serve::ServeArgs),
+    /// Accept mail over a local HTTP JSON API and send it with the configured login
+    ServeHttp(http::ServeHttpArgs),
+    /// Send all scheduled messages that are due, suitable for cron or systemd timers
+    RunDue,
+    /// Keep running, sending scheduled messages and retrying the outbox
+    Daemon {
+        #[arg(short, long, default_value_t = 60, help("seconds between checks"))]
+        interval: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ContactsAction {
+    /// Add a contact, or update the contact with the same address
+    Add {
+        email: String,
+        #[arg(short, long, help("display name"))]
+        name: Option<String>,
+        #[arg(short, long, help("short name to use with --to"))]
+        alias: Option<String>,
+        #[arg(short, long, help("add the contact to a group (use as --to @GROUP), can be repeated"))]
+        group: Vec<String>,
+    },
+    /// List all contacts and groups
+    List,
+    /// Remove a contact by alias or address, or a whole group with @GROUP
+    Remove { key: String },
+    /// Import contacts from a vCard or CSV (mailr, Google or Outlook) file
+    Import {
+        file: PathBuf,
+        #[arg(short, long, help("file format, detected from the file if not given"))]
+        format: Option<ContactFormat>,
+    },
+    /// Export all contacts to a vCard or CSV file
+    Export {
+        file: PathBuf,
+        #[arg(short, long, help("file format, detected from the extension if not given"))]
+        format: Option<ContactFormat>,
+        #[arg(long, default_value = "3.0")]
+        vcard_version: VcardVersion,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TemplatesAction {
+    /// List all templates
+    List,
+    /// Print a template
+    Show { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ScheduleAction {
+    /// List all scheduled messages
+    List,
+    /// Remove a scheduled message
+    Cancel { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum QueueAction {
+    /// List all queued messages
+    List,
+    /// Try to deliver all queued messages that are due
+    Flush {
+        #[arg(short, long, help("ignore the retry backoff and attempt every message now"))]
+        force: bool,
+    },
+    /// Remove a message from the outbox
+    Drop { id: String },
+}
+
+/// Like `Cli::parse_from`, but global flags like `-v` may also come before a command.
+/// clap's `args_conflicts_with_subcommands` would count them as well, so sending arguments are rejected here instead.
+fn parse_cli(argv: env::Args) -> Cli {
+    let matches = Cli::command().get_matches_from(argv);
+
+    if let Some((name, _)) = matches.subcommand() {
+        let cli = Cli::command();
+        let sending = cli.get_arguments().find(|arg| {
+            !arg.is_global_set() && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        });
+
+        if let Some(arg) = sending {
+            let arg = arg.get_long().map_or_else(|| arg.get_id().to_string(), |long| format!("--{long}"));
+            Cli::command()
+                .error(ErrorKind::ArgumentConflict, format!("the subcommand '{name}' cannot be used with '{arg}'"))
+                .exit();
+        }
+    }
+
+    Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
+/// Run one of the subcommands, other than `send`
+fn run_command(command: Command) {
+    let result = match command {
+        Command::Inbox(inbox_args) => ConfigManager::from_file().and_then(|config| inbox::list(&config, &inbox_args)),
+        Command::Read(read_args) => ConfigManager::from_file().and_then(|config| inbox::read(&config, &read_args)),
+        Command::Reply(reply_args) => ConfigManager::from_file().and_then(|config| reply::reply(&config, &reply_args)),
+        Command::Forward(forward_args) => {
+            ConfigManager::from_file().and_then(|config| reply::forward(&config, &forward_args))
+        }
+        Command::History(history_args) => history::show(&history_args),
+        Command::Contacts { action } => contacts_command(action),
+        Command::Templates { action } => templates_command(action),
+        Command::Queue { action } => queue_command(action),
+        Command::Schedule { action } => schedule_command(action),
+        Command::Merge(merge_args) => {
+            ConfigManager::from_file().and_then(|config| merge::merge(&config, &merge_args))
+        }
+        // Exit with the code of the command, so the caller still sees the failure
+        Command::Run(run_args) => run::run(&run_args).map(|code| process::exit(code)),
+        Command::Watch(watch_args) => ConfigManager::from_file().and_then(|config| watch::watch(&config, &watch_args)),
+        Command::Serve(serve_args) => {
+            ConfigManager::from_file().and_then(|mut config| serve::serve(&mut config, &serve_args))
+        }
+        Command::ServeHttp(http_args) => {
+            ConfigManager::from_file().and_then(|mut config| http::serve_http(&mut config, &http_args))
+        }
+        Command::RunDue => run_due(),
+        Command::Daemon { interval } => daemon(interval),
+        Command::Send(_) => unreachable!("handled in main"),
+    };
+
+    if let Err(err) = result {
+        error("command failed", err);
+    }
+}
+
+fn contacts_command(action: ContactsAction) -> anyhow::Result<()> {
+    let mut book = AddressBook::load()?;
+
+    match action {
+        ContactsAction::Add {
+            email,
+            name,
+            alias,
+            group,
+        } => {
+            let member = alias.clone().unwrap_or(email.clone());
+            let replaced = book.add(Contact { email, name, alias })?;
+            for group in group {
+                book.add_to_group(&group, &member);
+            }
+            book.save()?;
+
+            info(if replaced { "contact updated" } else { "contact added" });
+        }
+        ContactsAction::List => {
+            if book.contacts.is_empty() && book.groups.is_empty() {
+                info("the address book is empty");
+            }
+            book.print();
+        }
+        ContactsAction::Remove { key } => {
+            book.remove(&key)?;
+            book.save()?;
+            info(format!("removed '{key}'"));
+        }
+        ContactsAction::Import { file, format } => {
+            let format = match format {
+                Some(format) => format,
+                None => ContactFormat::detect(&file)?,
+            };
+            info(format!("importing {format} contacts from '{}'", file.display()));
+
+            let summary = book.import(contact_formats::read(&file, format)?);
+            for invalid in &summary.invalid {
+                warning(format!("skipped {invalid}"));
+            }
+            book.save()?;
+
+            info(format!(
+                "added: {}, merged duplicates: {}, invalid: {}",
+                summary.added,
+                summary.merged,
+                summary.invalid.len()
+            ));
+        }
+        ContactsAction::Export {
+            file,
+            format,
+            vcard_version,
+        } => {
+            let is_vcard = file
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("vcf") || ext.eq_ignore_ascii_case("vcard"));
+            let format = format.unwrap_or(if is_vcard { ContactFormat::Vcard } else { ContactFormat::Csv });
+
+            contact_formats::write(&file, format, vcard_version, &book.contacts)?;
+            info(format!("exported {} contacts to '{}'", book.contacts.len(), file.display()));
+        }
+    }
+
+    Ok(())
+}
+
+fn templates_command(action: TemplatesAction) -> anyhow::Result<()> {
+    match action {
+        TemplatesAction::List => {
+            let names = templates::list()?;
+            if names.is_empty() {
+                info(format!("no templates found, add them to '{}'", templates::dir()?.display()));
+            }
+
+            for name in names {
+                match templates::load(&name) {
+                    Ok(template) => println!("{} - {}", name.bold(), template.subject),
+                    Err(err) => warning(format!("{err}")),
+                }
+            }
+        }
+        TemplatesAction::Show { name } => {
+            print!("{}", fs::read_to_string(templates::path(&name)?)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn queue_command(action: QueueAction) -> anyhow::Result<()> {
+    let outbox = queue::Outbox::open()?;
+
+    match action {
+        QueueAction::List => {
+            let entries = outbox.list()?;
+            if entries.is_empty() {
+                info("the outbox is empty");
+            }
+
+            for entry in entries {
+                let status = if entry.failed {
+                    "failed".red()
+                } else {
+                    "pending".yellow()
+                };
+                let recipients: Vec<String> = entry.envelope.to().iter().map(|to| to.to_string()).collect();
+
+                println!(
+                    "{} [{status}] {} -> {} (attempts: {}, age: {}m)",
+                    entry.id.as_str().bold(),
+                    entry.subject,
+                    recipients.join(", "),
+                    entry.attempts,
+                    queue::now().saturating_sub(entry.created) / 60,
+                );
+                if let Some(err) = entry.last_error {
+                    println!("    last error: {}", err.dimmed());
+                }
+            }
+        }
+        QueueAction::Flush { force } => {
+            let config = ConfigManager::from_file()?;
+            let summary = outbox.flush(&config, force)?;
+            info(format!(
+                "sent: {}, deferred: {}, failed: {}",
+                summary.sent, summary.deferred, summary.failed
+            ));
+        }
+        QueueAction::Drop { id } => {
+            outbox.remove(&id)?;
+            info(format!("removed '{id}' from the outbox"));
+        }
+    }
+
+    Ok(())
+}
+
+fn schedule_command(action: ScheduleAction) -> anyhow::Result<()> {
+    let schedule = schedule::Schedule::open()?;
+
+    match action {
+        ScheduleAction::List => {
+            let entries = schedule.list()?;
+            if entries.is_empty() {
+                info("no messages are scheduled");
+            }
+
+            for entry in entries {
+                let due = DateTime::from_timestamp(entry.due as i64, 0).unwrap_or_default().with_timezone(&Local);
+                let recipients: Vec<String> = entry.envelope.to().iter().map(|to| to.to_string()).collect();
+
+                println!(
+                    "{} [{}] {} -> {}",
+                    entry.id.as_str().bold(),
+                    due.format("%Y-%m-%d %H:%M"),
+                    entry.subject,
+                    recipients.join(", "),
+                );
+            }
+        }
+        ScheduleAction::Cancel { id } => {
+            schedule.remove(&id)?;
+            info(format!("cancelled '{id}'"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Send due scheduled messages, then retry the outbox
+fn run_due() -> anyhow::Result<()> {
+    let config = ConfigManager::from_file()?;
+
+    let sent = schedule::Schedule::open()?.run_due(&config)?;
+    let summary = queue::Outbox::open()?.flush(&config, false)?;
+
+    if sent + summary.sent + summary.failed > 0 {
+        info(format!(
+            "scheduled: {sent}, outbox sent: {}, outbox failed: {}",
+            summary.sent, summary.failed
+        ));
+    }
+
+    Ok(())
+}
+
+fn daemon(interval: u64) -> anyhow::Result<()> {
+    info(format!("checking for due messages every {interval} seconds, press CTRL-C to stop"));
+
+    loop {
+        // A single failed run (e.g. a broken config) shouldn't stop the daemon
+        if let Err(err) = run_due() {
+            warning(format!("run failed: {err}"));
+        }
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+fn ask_send_email(cf: &ConfigManager) -> anyhow::Result<()> {
+    let book = AddressBook::load()?;
+    let validator_book = book.clone();
+
+    let email = inquire::Text::new("recipient email:")
+        .with_autocomplete(RecipientCompleter::new(&book))
+        .with_validator(move |to: &str| {
+            type Res = Result<Validation, Box<dyn std::error::Error + Send + Sync + 'static>>;
+            Res::Ok(match validator_book.resolve(to) {
+                Ok(_) => Validation::Valid,
+                Err(e) => Validation::Invalid(e.to_string().into()),
+            })
+        })
+        .prompt()?;
+
+    println!("");
+
+    let subject = inquire::Text::new("subject:").prompt()?;
+
+    println!("");
+
+    println!(
+        "{}",
+        "message body (press CTRL-Z to stop typing):".bright_green()
+    );
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let done_clone = done.clone();
+
+    // The msg body
+    let mut body = String::with_capacity(512);
+
+    // Handle control-c
+    ctrlc::set_handler(move || {
+        if done_clone.load(Ordering::SeqCst) {
+            panic!("CONTROL-C INTERRUPT HIT");
+        } 
+        done_clone.store(true, Ordering::SeqCst);
+    })?;
+
+    print!("{} ", ">".green());
+    let _ = stdout.flush();
+    for line in stdin.lines() {
+        let line = line?;
+        print!("{} ", ">".green());
+        let _ = stdout.flush();
+
+        if done.load(Ordering::SeqCst) {
+            break;
+        }
+
+        body.push_str(&line);
+        body.push('\n');
+    }
+    println!("\n");
+
+    let args = Args {
+        to: email,
+        subject,
+        msg: body,
+        ..Default::default()
+    };
+
+    send_or_queue(cf, &args)
+}
+
+pub fn main() {
+    log::init();
+
+    #[cfg(target_os = "windows")]
+    let _ = std::process::Command::new("cmd.exe")
+        .args(["/c", "cls"])
+        .status();
+
+    let argv = env::args();
+    let argc = argv.len();
+
+    let no_command_entered = argc <= 1;
+
+    if no_command_entered {
+        info(format!(
+            "You have entered no commands. To see a list of commands run this program with {}.\n",
+            "--help".blue()
+        ));
+
+        let config = ConfigManager::from_file();
+
+        match config {
+            Err(_) => {
+                warning("Failed to read config for login information.");
+                if let Err(_) | Ok(false) = inquire::prompt_confirmation(
+                    "Do you want to set and save your login information? (y/n)",
+                ) {
+                    info("aborting...");
+                    process::exit(0);
+                }
+                println!("\n");
+            }
+            Ok(config) => {
+                info("Existing configuration found.");
+                if let Err(_) | Ok(false) =
+                    inquire::prompt_confirmation("Do you want to send an Email? (y/n)")
+                {
+                    info("aborting...");
+                    process::exit(0);
+                }
+
+                if let Err(e) = ask_send_email(&config) {
+                    error("failed to gather input for sending email", e);
+                }
+                return;
+            }
+        }
+
+        // The user wants to configure their login data
+        let config =
+            ConfigManager::ask().unwrap_or_else(|err| error("failed to create config", err));
+
+        if let Err(err) = config.save() {
+            error("failed to save the config", err);
+        }
+
+        info("config saved successfully!\n");
+        info("To send an Email, run this program again with the arguments specified in the help menu");
+        hint(format!("Access the help menu by passing  {}  to the program on startup, or by simply restarting.", "--help".green()));
+
+        return;
+    }
+
+    // The user has entered arguments
+    let Cli { output, quiet, verbose, log_file, command, mut args } = parse_cli(argv);
+    log::set_format(output);
+    log::set_level(log::Level::from_flags(quiet, verbose));
+    if let Some(path) = log_file {
+        log::set_file(&path).unwrap_or_else(|err| error(format!("failed to open the log file '{}'", path.display()), err));
+    }
+    transcript::init();
+
+    match command {
+        // `mailr send ...` is the same as passing the arguments directly
+        Some(Command::Send(send_args)) => args = *send_args,
+        Some(command) => {
+            run_command(command);
+            return;
+        }
+        None => {}
+    }
+
+    if args.configure {
+        // The user wants to configure their login data
+        let config =
+            ConfigManager::ask().unwrap_or_else(|err| error("failed to create config", err));
+
+        if let Err(err) = config.save() {
+            error("failed to save the config", err);
+        }
+        return;
+    }
+
+    if let Err(err) = templates::apply(&mut args) {
+        error("failed to fill in the template", err);
+    }
+
+    // Is the user login saved?
+    let config = ConfigManager::from_file().unwrap_or_else(|err| {
+        error("can't read config", err);
+    });
+
+    // Was the email sent successfully?
+    if let Err(e) = send_or_queue(&config, &args) {
+        error("failed to send mail", e);
+    }
+}
@@ -1,17 +1,25 @@
-use crate::{crypto::Cipher, info, warning};
+use crate::{
+    crypto::{self, Cipher},
+    exit::{Classify, ErrorClass},
+    imap::ImapSession,
+    info, warning,
+};
 use anyhow::Ok;
 use inquire::{list_option::ListOption, validator::Validation};
 use lettre::{
+    message::Mailbox,
     transport::smtp::authentication::{Credentials, Mechanism},
-    Address,
+    Address, SmtpTransport,
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     env,
     error::Error,
     fmt, fs,
     path::{Path, PathBuf},
     process,
+    sync::{Mutex, OnceLock},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
@@ -89,6 +97,72 @@ impl Relay {
     }
 }
 
+/// IMAP server of the account, see `crate::imap`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImapSettings {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Append every delivered message to `sent_folder`
+    pub append_sent: bool,
+    pub sent_folder: String,
+    /// Login for the IMAP server, defaults to the SMTP login
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl Relay {
+    /// The IMAP server that belongs to the relay, if known
+    pub fn imap_settings(&self) -> Option<ImapSettings> {
+        match self {
+            Self::Outlook => Some(ImapSettings {
+                host: "outlook.office365.com".to_string(),
+                port: 993,
+                tls: true,
+                append_sent: true,
+                sent_folder: "Sent Items".to_string(),
+                username: None,
+            }),
+            Self::GMail => Some(ImapSettings {
+                host: "imap.gmail.com".to_string(),
+                port: 993,
+                tls: true,
+                // Gmail already files everything sent over its SMTP server
+                append_sent: false,
+                sent_folder: "[Gmail]/Sent Mail".to_string(),
+                username: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ImapSettings {
+    /// Ask the user for the IMAP server of a custom relay, returns `None` if they don't want one
+    pub fn ask() -> anyhow::Result<Option<Self>> {
+        if !inquire::prompt_confirmation("copy sent mail to a Sent folder over IMAP? (y/n)")? {
+            return Ok(None);
+        }
+
+        let host = inquire::prompt_text("(custom) IMAP server address:")?;
+        let port = inquire::CustomType::<u16>::new("(custom) IMAP server port:")
+            .with_default(993)
+            .with_validator(ConfigManager::port_validator)
+            .prompt()?;
+        let tls = inquire::prompt_confirmation("(custom) use TLS for IMAP? (y/n)")?;
+        let sent_folder = inquire::Text::new("Sent folder:").with_default("Sent").prompt()?;
+
+        Ok(Some(Self {
+            host,
+            port,
+            tls,
+            append_sent: true,
+            sent_folder,
+            username: None,
+        }))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Login {
     username: String,
@@ -96,11 +170,417 @@ pub struct Login {
     nonce: Vec<u8>,
 }
 
+/// Retry behaviour of the outbox, see `crate::queue`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueSettings {
+    /// Delay before the first retry, doubled after every failed attempt
+    pub base_delay_secs: u64,
+    /// Messages older than this are given up on
+    pub max_age_hours: u64,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            base_delay_secs: 60,
+            max_age_hours: 72,
+        }
+    }
+}
+
+/// Limits of the relay for bulk sending, see `crate::batch`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchSettings {
+    /// Most messages handed to the relay per minute, unlimited if not set
+    pub messages_per_minute: Option<u32>,
+    /// Most recipients in one SMTP transaction, larger envelopes are split
+    pub recipients_per_message: Option<usize>,
+    /// How often a message is sent again after the relay closed the connection (421)
+    #[serde(default = "BatchSettings::default_reconnect_attempts")]
+    pub reconnect_attempts: u32,
+    /// Messages sent at the same time, each over its own connection (only with the `async` feature)
+    #[serde(default = "BatchSettings::default_concurrency")]
+    pub concurrency: usize,
+}
+
+impl BatchSettings {
+    fn default_reconnect_attempts() -> u32 {
+        3
+    }
+
+    fn default_concurrency() -> usize {
+        4
+    }
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        Self {
+            messages_per_minute: None,
+            recipients_per_message: None,
+            reconnect_attempts: Self::default_reconnect_attempts(),
+            concurrency: Self::default_concurrency(),
+        }
+    }
+}
+
+/// Signature appended to every sent message, inline or read from a file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Signature {
+    pub text: Option<String>,
+    pub text_file: Option<PathBuf>,
+    pub html: Option<String>,
+    pub html_file: Option<PathBuf>,
+}
+
+impl Signature {
+    fn read(inline: &Option<String>, file: &Option<PathBuf>) -> anyhow::Result<Option<String>> {
+        match (inline, file) {
+            (Some(inline), _) => Ok(Some(inline.clone())),
+            (None, Some(file)) => Ok(Some(fs::read_to_string(file).map_err(|e| {
+                anyhow::anyhow!("failed to read signature '{}': {e}", file.display())
+            })?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The plain text variant, if configured
+    pub fn text(&self) -> anyhow::Result<Option<String>> {
+        Self::read(&self.text, &self.text_file)
+    }
+
+    /// The HTML variant, if configured
+    pub fn html(&self) -> anyhow::Result<Option<String>> {
+        Self::read(&self.html, &self.html_file)
+    }
+
+    /// Ask the user for a signature, returns `None` if they don't want one
+    pub fn ask() -> anyhow::Result<Option<Self>> {
+        if !inquire::prompt_confirmation("add a signature to sent mail? (y/n)")? {
+            return Ok(None);
+        }
+
+        let mut signature = Self::default();
+
+        const INLINE: &str = "type it in (use \\n for line breaks)";
+        const FILE: &str = "read it from a file";
+
+        if inquire::Select::new("plain text signature:", vec![INLINE, FILE]).prompt()? == INLINE {
+            signature.text = Some(inquire::prompt_text("signature:")?.replace("\\n", "\n"));
+        } else {
+            signature.text_file = Some(inquire::prompt_text("signature file:")?.into());
+        }
+
+        let html_file = inquire::Text::new("HTML signature file (leave empty for none):").prompt()?;
+        if !html_file.trim().is_empty() {
+            signature.html_file = Some(html_file.trim().into());
+        }
+
+        Ok(Some(signature))
+    }
+}
+
+/// What to keep in the sent-mail history, see `crate::history`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistorySettings {
+    pub enabled: bool,
+    /// Also store the (encrypted) text body of every message
+    pub store_bodies: bool,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            store_bodies: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    Rsa,
+    Ed25519,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// DKIM signing of sent mail, see `crate::dkim`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DkimSettings {
+    pub selector: String,
+    pub domain: String,
+    pub private_key: PathBuf,
+    #[serde(default = "DkimSettings::default_algorithm")]
+    pub algorithm: DkimAlgorithm,
+    /// Headers covered by the signature
+    #[serde(default = "DkimSettings::default_headers")]
+    pub headers: Vec<String>,
+    #[serde(default = "DkimSettings::default_canonicalization")]
+    pub header_canonicalization: Canonicalization,
+    #[serde(default = "DkimSettings::default_canonicalization")]
+    pub body_canonicalization: Canonicalization,
+}
+
+impl DkimSettings {
+    fn default_algorithm() -> DkimAlgorithm {
+        DkimAlgorithm::Rsa
+    }
+
+    fn default_headers() -> Vec<String> {
+        ["From", "To", "Subject", "Date", "Message-ID", "MIME-Version", "Content-Type"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn default_canonicalization() -> Canonicalization {
+        Canonicalization::Relaxed
+    }
+}
+
+/// OpenPGP keys for `--sign` and `--encrypt`, see `crate::pgp`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PgpSettings {
+    /// Secret key used for signing, armored or binary
+    pub secret_key: Option<PathBuf>,
+    /// Directory with public keys (`.asc`, `.pgp`, `.gpg`), `keyring` in the data directory if not set
+    pub keyring: Option<PathBuf>,
+    /// Public key files of specific recipients, by address
+    pub public_keys: BTreeMap<String, PathBuf>,
+    /// Also encrypt to the own key, so sent mail stays readable
+    pub encrypt_to_self: bool,
+}
+
+impl Default for PgpSettings {
+    fn default() -> Self {
+        Self {
+            secret_key: None,
+            keyring: None,
+            public_keys: BTreeMap::new(),
+            encrypt_to_self: true,
+        }
+    }
+}
+
+/// S/MIME certificate and key for `--smime`, see `crate::smime`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SmimeSettings {
+    /// Own certificate (PEM or DER, optionally followed by the chain), or a PKCS#12 bundle (`.p12`, `.pfx`)
+    pub certificate: PathBuf,
+    /// Private key (PEM or DER), not needed with a PKCS#12 bundle
+    pub key: Option<PathBuf>,
+    /// Directory with the certificates of recipients, `certs` in the data directory if not set
+    pub certificates: Option<PathBuf>,
+    /// Also encrypt to the own certificate, so sent mail stays readable
+    #[serde(default = "SmimeSettings::default_encrypt_to_self")]
+    pub encrypt_to_self: bool,
+    /// Password of the key or bundle, encrypted like the login password
+    #[serde(default)]
+    password: Vec<u8>,
+    #[serde(default)]
+    nonce: Vec<u8>,
+}
+
+impl SmimeSettings {
+    fn default_encrypt_to_self() -> bool {
+        true
+    }
+
+    /// The decrypted password of the key, `None` if none was stored
+    pub fn password(&self) -> anyhow::Result<Option<String>> {
+        if self.password.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Cipher::new().decrypt(&self.password, self.nonce.as_slice().into())?))
+    }
+
+    /// Ask the user for their certificate, returns `None` if they don't use S/MIME
+    pub fn ask() -> anyhow::Result<Option<Self>> {
+        if !inquire::prompt_confirmation("sign or encrypt mail with S/MIME? (y/n)")? {
+            return Ok(None);
+        }
+
+        let certificate: PathBuf = inquire::prompt_text("certificate or PKCS#12 file:")?.trim().into();
+        let is_bundle = certificate
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"));
+        let key = match is_bundle {
+            true => None,
+            false => Some(inquire::prompt_text("private key file:")?.trim().into()),
+        };
+
+        let password_plain = inquire::prompt_secret("key password (leave empty for none):")?;
+        let mut password = Vec::new();
+        let mut nonce = Vec::new();
+        if !password_plain.is_empty() {
+            nonce = Cipher::new().encrypt(&password_plain, &mut password)?.to_vec();
+        }
+
+        Ok(Some(Self {
+            certificate,
+            key,
+            certificates: None,
+            encrypt_to_self: true,
+            password,
+            nonce,
+        }))
+    }
+}
+
+/// A login of a local application for `mailr serve`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServeUser {
+    pub username: String,
+    /// Encrypted like the login password
+    password: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+impl ServeUser {
+    pub fn new(username: &str, password: &str) -> anyhow::Result<Self> {
+        let mut encrypted = Vec::new();
+        let nonce = Cipher::new().encrypt(password, &mut encrypted)?;
+
+        Ok(Self {
+            username: username.to_string(),
+            password: encrypted,
+            nonce: nonce.to_vec(),
+        })
+    }
+
+    /// Whether `password` is the password of this user
+    pub fn check(&self, password: &str) -> bool {
+        Cipher::new()
+            .decrypt(&self.password, self.nonce.as_slice().into())
+            .is_ok_and(|stored| crypto::secret_eq(&stored, password))
+    }
+}
+
+/// The local SMTP server, see `crate::serve`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ServeSettings {
+    /// Address to listen on if `--listen` isn't given
+    pub listen: Option<String>,
+    /// Logins of local applications, if there are none no login is needed
+    #[serde(default)]
+    pub users: Vec<ServeUser>,
+}
+
+/// The HTTP API of `mailr serve-http`, see `crate::http`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HttpSettings {
+    /// Address to listen on if `--listen` isn't given
+    pub listen: Option<String>,
+    /// Bearer token of the API, encrypted like the login password
+    #[serde(default)]
+    token: Vec<u8>,
+    #[serde(default)]
+    nonce: Vec<u8>,
+}
+
+impl HttpSettings {
+    pub fn has_token(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    pub fn set_token(&mut self, token: &str) -> anyhow::Result<()> {
+        self.nonce = Cipher::new().encrypt(token, &mut self.token)?.to_vec();
+        Ok(())
+    }
+
+    /// Whether `token` is the configured token
+    pub fn check_token(&self, token: &str) -> bool {
+        self.has_token()
+            && Cipher::new()
+                .decrypt(&self.token, self.nonce.as_slice().into())
+                .is_ok_and(|stored| crypto::secret_eq(&stored, token))
+    }
+}
+
+/// An address this account is allowed to send as
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub address: String,
+    pub name: Option<String>,
+}
+
+/// The From header of sent mail, see `ConfigManager::sender`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SenderSettings {
+    /// Display name of the default address
+    pub name: Option<String>,
+    /// Default From address, the login if not set
+    pub address: Option<String>,
+    /// Other addresses (aliases, shared mailboxes) that can be picked with `--from`
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+}
+
+/// Defaults for every message sent with this profile, see `crate::headers`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MessageSettings {
+    /// Domain of generated Message-IDs, the host name if not set
+    pub message_id_domain: Option<String>,
+    /// Envelope sender (`MAIL FROM`, which becomes the `Return-Path`), the `From` address if not set
+    pub envelope_from: Option<String>,
+    /// Headers added to every message, `--header` overrides them
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    Maildir,
+    Mbox,
+}
+
+/// Where to keep copies of delivered mail, see `crate::archive`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SentArchive {
+    pub format: ArchiveFormat,
+    /// The Maildir directory or mbox file
+    pub path: PathBuf,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     login: Login,
     #[serde(rename(serialize = "relay", deserialize = "relay"))]
     pub relay_settings: RelaySettings,
+    #[serde(default)]
+    pub queue: QueueSettings,
+    #[serde(default)]
+    pub batch: BatchSettings,
+    #[serde(default)]
+    pub signature: Option<Signature>,
+    #[serde(default)]
+    pub history: HistorySettings,
+    #[serde(default)]
+    pub sent_archive: Option<SentArchive>,
+    #[serde(default)]
+    pub imap: Option<ImapSettings>,
+    #[serde(default)]
+    pub message: MessageSettings,
+    #[serde(default)]
+    pub sender: SenderSettings,
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+    #[serde(default)]
+    pub pgp: PgpSettings,
+    #[serde(default)]
+    pub smime: Option<SmimeSettings>,
+    #[serde(default)]
+    pub serve: ServeSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -111,6 +591,12 @@ pub struct ConfigManager {
     password_str: Option<String>,
     #[serde(skip)]
     store_loc: Vec<SaveLocation>,
+    /// The SMTP transport, created on first use and reused for every message after that
+    #[serde(skip)]
+    pub(crate) transport: OnceLock<SmtpTransport>,
+    /// The IMAP session for the Sent folder, see `append_sent`
+    #[serde(skip)]
+    pub(crate) sent_session: Mutex<Option<ImapSession>>,
 }
 
 impl ConfigManager {
@@ -125,6 +611,15 @@ impl ConfigManager {
         })
     }
 
+    pub fn port_validator(port: &u16) -> Result<Validation, Box<dyn Error + Send + Sync + 'static>> {
+        type Res = Result<Validation, Box<dyn Error + Send + Sync + 'static>>;
+        Res::Ok(if (1..65535).contains(port) {
+            Validation::Valid
+        } else {
+            Validation::Invalid("Valid port range is 1 to 65535".into())
+        })
+    }
+
     const fn local_file_loc() -> &'static str {
         "./.mailr.toml"
     }
@@ -148,17 +643,36 @@ impl ConfigManager {
         // NOTE: if compiling fails here, you have to implement a function that returns the global config file path for your OS.
     }
 
+    /// Directory for data that lives next to the global config (outbox, ...)
+    pub fn data_dir() -> anyhow::Result<PathBuf> {
+        let global_path = Self::global_file_loc()?;
+        let parent = global_path
+            .parent()
+            .ok_or(anyhow::anyhow!("global config '{}' has no parent directory", global_path.display()))?;
+
+        let dir = if parent.ends_with(env!("CARGO_PKG_NAME")) {
+            parent.to_path_buf()
+        } else {
+            parent.join(env!("CARGO_PKG_NAME"))
+        };
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
     /// Clone username & password into `Credentials`
     pub fn credentials(&self) -> Credentials {
-        Credentials::new(
-            self.config.login.username.clone(),
-            self.password_str.as_ref().unwrap().clone(),
-        )
+        Credentials::new(self.config.login.username.clone(), self.password().to_string())
     }
 
     /// Try to read the config from a file.  
     /// Strategy: First check local, then check global.
     pub fn from_file() -> anyhow::Result<Self> {
+        Self::read_file().class(ErrorClass::Config)
+    }
+
+    /// `from_file`, before its errors are marked as config errors
+    fn read_file() -> anyhow::Result<Self> {
         let local_path = Path::new(Self::local_file_loc());
         let contents: String;
 
@@ -176,6 +690,7 @@ impl ConfigManager {
                 &des.config.login.password,
                 des.config.login.nonce.as_slice().into(),
             )?);
+            des.store_loc = vec![SaveLocation::Local];
             return Ok(des);
         }
 
@@ -193,6 +708,7 @@ impl ConfigManager {
                 &des.config.login.password,
                 des.config.login.nonce.as_slice().into(),
             )?);
+            des.store_loc = vec![SaveLocation::Global];
             return Ok(des);
         }
 
@@ -204,10 +720,80 @@ impl ConfigManager {
         )
     }
 
+    /// Name of the config ("profile") in use, "local" or "global"
+    pub fn profile(&self) -> &'static str {
+        match self.store_loc.first() {
+            Some(SaveLocation::Local) => "local",
+            Some(SaveLocation::Global) => "global",
+            None => "unsaved",
+        }
+    }
+
     pub fn username(&self) -> &str {
         &self.config.login.username
     }
 
+    /// All identities this account may send as, the default one first
+    pub fn identities(&self) -> Vec<Identity> {
+        let sender = &self.config.sender;
+        let default = Identity {
+            address: sender.address.clone().unwrap_or(self.username().to_string()),
+            name: sender.name.clone(),
+        };
+
+        let mut identities = vec![default];
+        identities.extend(sender.identities.iter().cloned());
+        identities
+    }
+
+    /// The From mailbox: the default identity, or the identity with the address of `from`.
+    /// A display name in `from` (`Name <address>`) overrides the configured one.
+    pub fn sender(&self, from: Option<&str>) -> anyhow::Result<Mailbox> {
+        let identities = self.identities();
+
+        let Some(from) = from else {
+            let default = &identities[0];
+            let address = default
+                .address
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid sender address '{}': {e}", default.address))?;
+            return Ok(Mailbox::new(default.name.clone(), address));
+        };
+
+        let mailbox: Mailbox = from
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid --from '{from}': {e}"))?;
+
+        let identity = identities
+            .iter()
+            .find(|identity| identity.address.eq_ignore_ascii_case(mailbox.email.as_ref()))
+            .ok_or(anyhow::anyhow!(
+                "'{}' is not one of the configured identities ({})",
+                mailbox.email,
+                identities.iter().map(|identity| identity.address.as_str()).collect::<Vec<_>>().join(", ")
+            ))?;
+
+        Ok(Mailbox::new(mailbox.name.or(identity.name.clone()), mailbox.email))
+    }
+
+    /// The decrypted password
+    pub fn password(&self) -> &str {
+        self.password_str.as_deref().unwrap_or_default()
+    }
+
+    /// Write the config back to the file it was read from, without asking.
+    /// For commands that change a setting, `save` is for `--configure`.
+    pub fn update(&self) -> anyhow::Result<()> {
+        let path = match self.store_loc.first() {
+            Some(SaveLocation::Local) => PathBuf::from(Self::local_file_loc()),
+            Some(SaveLocation::Global) => Self::global_file_loc()?,
+            None => anyhow::bail!("the config wasn't read from a file"),
+        };
+
+        fs::write(&path, toml::to_string_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("failed to write config '{}': {e}", path.display()))
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
         let store_local = self.store_loc.contains(&SaveLocation::Local);
         let store_global = self.store_loc.contains(&SaveLocation::Global);
@@ -265,8 +851,10 @@ impl ConfigManager {
         }
         Ok(())
     }
-    /// Ask the user for config values
+    /// Ask the user for config values.
+    /// Settings the prompts don't cover (identities, DKIM, PGP, `serve`, ...) are kept from the current config.
     pub fn ask() -> anyhow::Result<Self> {
+        let current = Self::read_file().ok();
         let cipher = Cipher::new();
 
         // Ask user for email:
@@ -285,6 +873,9 @@ impl ConfigManager {
         // Drop the password_plain early
         drop(password_plain);
 
+        let display_name = inquire::Text::new("display name (leave empty for none):").prompt()?;
+        let display_name = Some(display_name.trim().to_string()).filter(|name| !name.is_empty());
+
         let relay = inquire::Select::new(
             "which relay to use:",
             vec![Relay::Outlook, Relay::GMail, Relay::Custom],
@@ -295,17 +886,8 @@ impl ConfigManager {
             // Read custom settings
             let addr = inquire::prompt_text("(custom) server address:")?;
 
-            let validate_port = |port: &u16| {
-                type Res = Result<Validation, Box<dyn Error + Send + Sync + 'static>>;
-                Res::Ok(if (1..65535).contains(port) {
-                    Validation::Valid
-                } else {
-                    Validation::Invalid("Valid port range is 1 to 65535".into())
-                })
-            };
-
             let port = inquire::CustomType::<u16>::new("(custom) server port:")
-                .with_validator(validate_port)
+                .with_validator(Self::port_validator)
                 .prompt()?;
 
             let tls = inquire::prompt_confirmation("(custom) use TLS? (y/n)")?;
@@ -330,6 +912,14 @@ impl ConfigManager {
             relay.settings()
         };
 
+        let imap = match relay.imap_settings() {
+            Some(imap) => Some(imap),
+            None => ImapSettings::ask()?,
+        };
+
+        let signature = Signature::ask()?;
+        let smime = SmimeSettings::ask()?;
+
         let store_loc = inquire::MultiSelect::new(
             "location to store email & password:",
             vec![SaveLocation::Local, SaveLocation::Global],
@@ -344,17 +934,53 @@ impl ConfigManager {
         })
         .prompt()?;
 
-        Ok(Self {
-            config: Config {
-                login: Login {
-                    username: email,
-                    password,
-                    nonce: nonce.to_vec(),
+        let login = Login {
+            username: email,
+            password,
+            nonce: nonce.to_vec(),
+        };
+
+        let config = match current {
+            Some(ConfigManager { config, .. }) => Config {
+                login,
+                relay_settings,
+                signature,
+                imap,
+                sender: SenderSettings {
+                    name: display_name,
+                    ..config.sender
                 },
+                smime,
+                ..config
+            },
+            None => Config {
+                login,
                 relay_settings,
+                queue: QueueSettings::default(),
+                batch: BatchSettings::default(),
+                signature,
+                history: HistorySettings::default(),
+                sent_archive: None,
+                imap,
+                message: MessageSettings::default(),
+                sender: SenderSettings {
+                    name: display_name,
+                    ..Default::default()
+                },
+                dkim: None,
+                pgp: PgpSettings::default(),
+                smime,
+                serve: ServeSettings::default(),
+                http: HttpSettings::default(),
             },
+        };
+
+        Ok(Self {
+            config,
             password_str: None,
             store_loc,
+            transport: OnceLock::new(),
+            sent_session: Mutex::new(None),
         })
     }
 }
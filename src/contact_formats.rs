@@ -0,0 +1,516 @@
+//! Import & export of contacts in vCard (3.0 / 4.0) and CSV formats.  
+//! Supported CSV flavours are mailr's own (`email,name,alias`), Google Contacts and Outlook exports.  
+
+use std::{fmt, fs, path::Path};
+
+use clap::ValueEnum;
+
+use crate::contacts::Contact;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ContactFormat {
+    /// vCard 3.0 or 4.0 (.vcf)
+    Vcard,
+    /// mailr's own CSV with email, name and alias columns
+    Csv,
+    /// Google Contacts CSV export
+    Google,
+    /// Outlook CSV export
+    Outlook,
+}
+
+impl fmt::Display for ContactFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Vcard => "vCard",
+                Self::Csv => "CSV",
+                Self::Google => "Google CSV",
+                Self::Outlook => "Outlook CSV",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum VcardVersion {
+    #[value(name = "3.0")]
+    V3,
+    #[value(name = "4.0")]
+    V4,
+}
+
+/// A contact read from a file, with a description of where it was found for error reports.
+/// An entry without any address is kept with an empty `email`, so that the import can report it.
+pub struct ImportedContact {
+    pub location: String,
+    pub contact: Contact,
+}
+
+impl ContactFormat {
+    /// Guess the format from the extension and, for CSV files, the header row
+    pub fn detect(path: &Path) -> anyhow::Result<Self> {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if ext == "vcf" || ext == "vcard" {
+            return Ok(Self::Vcard);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        if contents.trim_start().to_uppercase().starts_with("BEGIN:VCARD") {
+            return Ok(Self::Vcard);
+        }
+
+        let header = contents.lines().next().unwrap_or_default().to_lowercase();
+        if header.contains("e-mail 1 - value") {
+            Ok(Self::Google)
+        } else if header.contains("e-mail address") {
+            Ok(Self::Outlook)
+        } else {
+            Ok(Self::Csv)
+        }
+    }
+}
+
+/// Read all contacts in `path`.
+/// A vCard or CSV row with several addresses results in several contacts.
+pub fn read(path: &Path, format: ContactFormat) -> anyhow::Result<Vec<ImportedContact>> {
+    match format {
+        ContactFormat::Vcard => read_vcard(&fs::read_to_string(path)?),
+        _ => read_csv(path, format),
+    }
+}
+
+/// Write `contacts` to `path`
+pub fn write(path: &Path, format: ContactFormat, version: VcardVersion, contacts: &[Contact]) -> anyhow::Result<()> {
+    match format {
+        ContactFormat::Vcard => Ok(fs::write(path, write_vcard(contacts, version))?),
+        _ => write_csv(path, format, contacts),
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Split a name into first and last name, at the last space
+fn split_name(name: &str) -> (&str, &str) {
+    name.rsplit_once(' ').unwrap_or((name, ""))
+}
+
+fn read_csv(path: &Path, format: ContactFormat) -> anyhow::Result<Vec<ImportedContact>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+    let headers: Vec<String> = reader.headers()?.iter().map(|h| h.trim().to_lowercase()).collect();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+
+    let email_columns: Vec<usize> = match format {
+        ContactFormat::Google => headers
+            .iter()
+            .enumerate()
+            .filter(|(_, header)| header.starts_with("e-mail ") && header.ends_with(" - value"))
+            .map(|(i, _)| i)
+            .collect(),
+        ContactFormat::Outlook => ["e-mail address", "e-mail 2 address", "e-mail 3 address"]
+            .iter()
+            .filter_map(|name| column(name))
+            .collect(),
+        _ => column("email").into_iter().collect(),
+    };
+    if email_columns.is_empty() {
+        anyhow::bail!("'{}' has no e-mail column for the {format} format", path.display());
+    }
+
+    let name_column = column("name");
+    let first_name_column = column("first name").or(column("given name"));
+    let last_name_column = column("last name").or(column("family name"));
+    let alias_column = match format {
+        ContactFormat::Csv => column("alias"),
+        _ => column("nickname"),
+    };
+
+    let mut contacts = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        // +2: the header is line 1
+        let location = format!("line {}", i + 2);
+        let record = record.map_err(|e| anyhow::anyhow!("failed to read {location}: {e}"))?;
+        let field = |column: Option<usize>| column.and_then(|column| record.get(column)).and_then(non_empty);
+
+        let name = field(name_column).or_else(|| {
+            let parts: Vec<String> = [field(first_name_column), field(last_name_column)]
+                .into_iter()
+                .flatten()
+                .collect();
+            (!parts.is_empty()).then(|| parts.join(" "))
+        });
+        let mut alias = field(alias_column);
+
+        // Google puts several addresses in one cell, separated by " ::: "
+        let mut emails: Vec<String> = email_columns
+            .iter()
+            .filter_map(|&column| field(Some(column)))
+            .flat_map(|cell| cell.split(":::").filter_map(non_empty).collect::<Vec<_>>())
+            .collect();
+        if emails.is_empty() {
+            emails.push(String::new());
+        }
+
+        for email in emails {
+            contacts.push(ImportedContact {
+                location: location.clone(),
+                contact: Contact {
+                    email,
+                    name: name.clone(),
+                    // Only the first address gets the alias
+                    alias: alias.take(),
+                },
+            });
+        }
+    }
+
+    Ok(contacts)
+}
+
+fn write_csv(path: &Path, format: ContactFormat, contacts: &[Contact]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+
+    match format {
+        ContactFormat::Google => {
+            writer.write_record(["Name", "Given Name", "Family Name", "Nickname", "E-mail 1 - Type", "E-mail 1 - Value"])?;
+            for contact in contacts {
+                let name = contact.name.as_deref().unwrap_or_default();
+                let (first, last) = split_name(name);
+                let alias = contact.alias.as_deref().unwrap_or_default();
+                writer.write_record([name, first, last, alias, "* Other", &contact.email])?;
+            }
+        }
+        ContactFormat::Outlook => {
+            writer.write_record(["First Name", "Last Name", "Nickname", "E-mail Address", "E-mail Display Name"])?;
+            for contact in contacts {
+                let name = contact.name.as_deref().unwrap_or_default();
+                let (first, last) = split_name(name);
+                let alias = contact.alias.as_deref().unwrap_or_default();
+                writer.write_record([first, last, alias, &contact.email, name])?;
+            }
+        }
+        _ => {
+            writer.write_record(["email", "name", "alias"])?;
+            for contact in contacts {
+                writer.write_record([
+                    contact.email.as_str(),
+                    contact.name.as_deref().unwrap_or_default(),
+                    contact.alias.as_deref().unwrap_or_default(),
+                ])?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Undo vCard escaping (`\,` `\;` `\\` `\n`)
+fn unescape_vcard(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// Split a structured value (`N`, `NICKNAME`) at every `separator` that isn't escaped, and unescape the components
+fn split_vcard(value: &str, separator: char) -> Vec<String> {
+    let mut components = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == separator => {
+                components.push(unescape_vcard(&value[start..i]));
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    components.push(unescape_vcard(&value[start..]));
+
+    components
+}
+
+fn escape_vcard(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+fn read_vcard(contents: &str) -> anyhow::Result<Vec<ImportedContact>> {
+    // Unfold continuation lines, which start with a space or tab
+    let mut lines: Vec<String> = Vec::new();
+    for line in contents.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut contacts = Vec::new();
+    let mut card_nr = 0;
+
+    let mut name: Option<String> = None;
+    let mut structured_name: Option<String> = None;
+    let mut alias: Option<String> = None;
+    let mut emails: Vec<String> = Vec::new();
+
+    for line in lines {
+        let Some((property, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Drop parameters (`EMAIL;TYPE=work`) and group prefixes (`item1.EMAIL`)
+        let property = property.split(';').next().unwrap_or_default();
+        let property = property.rsplit('.').next().unwrap_or_default().to_uppercase();
+
+        match property.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => {
+                card_nr += 1;
+                name = None;
+                structured_name = None;
+                alias = None;
+                emails.clear();
+            }
+            "FN" => name = non_empty(&unescape_vcard(value)),
+            "N" => {
+                // N:Family;Given;Additional;Prefix;Suffix
+                let parts = split_vcard(value, ';');
+                let given = parts.get(1).map(String::as_str).unwrap_or_default();
+                let family = parts.first().map(String::as_str).unwrap_or_default();
+                structured_name = non_empty(&format!("{given} {family}"));
+            }
+            "NICKNAME" => alias = split_vcard(value, ',').first().and_then(|nick| non_empty(nick)),
+            "EMAIL" => emails.extend(non_empty(&unescape_vcard(value))),
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                let name = name.take().or(structured_name.take());
+                let mut alias = alias.take();
+                if emails.is_empty() {
+                    emails.push(String::new());
+                }
+
+                for email in emails.drain(..) {
+                    contacts.push(ImportedContact {
+                        location: format!("card {card_nr}"),
+                        contact: Contact {
+                            email,
+                            name: name.clone(),
+                            alias: alias.take(),
+                        },
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(contacts)
+}
+
+/// Append a content line, folded at 75 octets as required by the vCard spec
+fn push_vcard_line(out: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn write_vcard(contacts: &[Contact], version: VcardVersion) -> String {
+    let mut out = String::new();
+
+    let (version_line, email_property) = match version {
+        VcardVersion::V3 => ("VERSION:3.0", "EMAIL;TYPE=INTERNET"),
+        VcardVersion::V4 => ("VERSION:4.0", "EMAIL"),
+    };
+
+    for contact in contacts {
+        let name = contact.name.clone().unwrap_or(contact.email.clone());
+        let (first, last) = split_name(&name);
+
+        push_vcard_line(&mut out, "BEGIN:VCARD");
+        push_vcard_line(&mut out, version_line);
+        push_vcard_line(&mut out, &format!("FN:{}", escape_vcard(&name)));
+        push_vcard_line(&mut out, &format!("N:{};{};;;", escape_vcard(last), escape_vcard(first)));
+        push_vcard_line(&mut out, &format!("{email_property}:{}", contact.email));
+        if let Some(alias) = &contact.alias {
+            push_vcard_line(&mut out, &format!("NICKNAME:{}", escape_vcard(alias)));
+        }
+        push_vcard_line(&mut out, "END:VCARD");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn contact(email: &str, name: Option<&str>, alias: Option<&str>) -> Contact {
+        Contact {
+            email: email.to_string(),
+            name: name.map(String::from),
+            alias: alias.map(String::from),
+        }
+    }
+
+    fn contacts(imported: Vec<ImportedContact>) -> Vec<(String, Option<String>, Option<String>)> {
+        imported
+            .into_iter()
+            .map(|ImportedContact { contact, .. }| (contact.email, contact.name, contact.alias))
+            .collect()
+    }
+
+    /// Read `contents` as a CSV file of `format`
+    fn read_csv_str(name: &str, contents: &str, format: ContactFormat) -> Vec<ImportedContact> {
+        let path = env::temp_dir().join(format!("mailr-test-{}-{name}.csv", process::id()));
+        fs::write(&path, contents).unwrap();
+        let imported = read_csv(&path, format);
+        fs::remove_file(&path).unwrap();
+        imported.unwrap()
+    }
+
+    #[test]
+    fn vcard_round_trip() {
+        let written = [
+            contact("ann@example.com", Some("Ann Lee"), Some("ann")),
+            contact("doe@example.com", Some("Doe, John; Jr."), Some("jd,x;y")),
+            contact("back@example.com", Some("Back\\slash"), None),
+            contact("plain@example.com", None, None),
+        ];
+
+        for version in [VcardVersion::V3, VcardVersion::V4] {
+            let read = read_vcard(&write_vcard(&written, version)).unwrap();
+
+            assert_eq!(
+                contacts(read),
+                [
+                    ("ann@example.com".into(), Some("Ann Lee".into()), Some("ann".into())),
+                    ("doe@example.com".into(), Some("Doe, John; Jr.".into()), Some("jd,x;y".into())),
+                    ("back@example.com".into(), Some("Back\\slash".into()), None),
+                    ("plain@example.com".into(), Some("plain@example.com".into()), None),
+                ],
+                "{version:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn vcard_structured_values_split_on_unescaped_separators() {
+        let card = "BEGIN:VCARD\r\nVERSION:3.0\r\nN:Smith\\;Jones;Anna\\, Maria;;;\r\nNICKNAME:a\\,b,second\r\nEMAIL:anna@example.com\r\nEND:VCARD\r\n";
+
+        assert_eq!(
+            contacts(read_vcard(card).unwrap()),
+            [("anna@example.com".into(), Some("Anna, Maria Smith;Jones".into()), Some("a,b".into()))]
+        );
+    }
+
+    #[test]
+    fn vcard_folded_lines_and_several_addresses() {
+        let long_name = "A".repeat(100);
+        let card = write_vcard(&[contact("a@example.com", Some(&long_name), None)], VcardVersion::V4);
+        assert!(card.lines().all(|line| line.len() <= 75));
+        assert_eq!(contacts(read_vcard(&card).unwrap())[0].1, Some(long_name));
+
+        let card = "BEGIN:VCARD\nFN:Two\nNICKNAME:two\nitem1.EMAIL;TYPE=work:one@example.com\nEMAIL:two@example.com\nEND:VCARD\n";
+        assert_eq!(
+            contacts(read_vcard(card).unwrap()),
+            [
+                ("one@example.com".into(), Some("Two".into()), Some("two".into())),
+                ("two@example.com".into(), Some("Two".into()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn google_csv_splits_addresses() {
+        let csv = "Name,Given Name,Family Name,Nickname,E-mail 1 - Type,E-mail 1 - Value,E-mail 2 - Type,E-mail 2 - Value\n\
+                   Ann Lee,Ann,Lee,ann,* Other,ann@example.com ::: lee@example.com,Work,ann@work.example.com\n\
+                   ,Bob,Stone,,* Other,bob@example.com,,\n";
+
+        assert_eq!(
+            contacts(read_csv_str("google", csv, ContactFormat::Google)),
+            [
+                ("ann@example.com".into(), Some("Ann Lee".into()), Some("ann".into())),
+                ("lee@example.com".into(), Some("Ann Lee".into()), None),
+                ("ann@work.example.com".into(), Some("Ann Lee".into()), None),
+                ("bob@example.com".into(), Some("Bob Stone".into()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn outlook_csv() {
+        let csv = "First Name,Middle Name,Last Name,Nickname,E-mail Address,E-mail 2 Address,E-mail Display Name\n\
+                   Ann,,Lee,ann,ann@example.com,lee@example.com,Ann Lee\n\
+                   ,,,,,,Nobody\n";
+
+        assert_eq!(
+            contacts(read_csv_str("outlook", csv, ContactFormat::Outlook)),
+            [
+                ("ann@example.com".into(), Some("Ann Lee".into()), Some("ann".into())),
+                ("lee@example.com".into(), Some("Ann Lee".into()), None),
+                (String::new(), None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let written = [
+            contact("ann@example.com", Some("Ann Lee"), Some("ann")),
+            contact("doe@example.com", Some("Doe, \"JD\" John"), None),
+        ];
+
+        for format in [ContactFormat::Csv, ContactFormat::Google, ContactFormat::Outlook] {
+            let path = env::temp_dir().join(format!("mailr-test-{}-{format:?}-round-trip.csv", process::id()));
+            write_csv(&path, format, &written).unwrap();
+            assert_eq!(ContactFormat::detect(&path).unwrap(), format);
+
+            let read = read_csv(&path, format);
+            fs::remove_file(&path).unwrap();
+
+            assert_eq!(
+                contacts(read.unwrap()),
+                [
+                    ("ann@example.com".into(), Some("Ann Lee".into()), Some("ann".into())),
+                    ("doe@example.com".into(), Some("Doe, \"JD\" John".into()), None),
+                ],
+                "{format:?}"
+            );
+        }
+    }
+}
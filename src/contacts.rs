@@ -0,0 +1,433 @@
+//! Local address book.  
+//! Contacts have an address, an optional display name and an optional alias (`--to boss`).  
+//! Groups expand to many recipients (`--to @oncall`).  
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+};
+
+use colored::Colorize;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use inquire::{
+    autocompletion::Replacement,
+    validator::{ErrorMessage, Validation},
+    Autocomplete, CustomUserError,
+};
+use lettre::{message::Mailbox, Address};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigManager, contact_formats::ImportedContact};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub email: String,
+    pub name: Option<String>,
+    pub alias: Option<String>,
+}
+
+impl Contact {
+    pub fn mailbox(&self) -> anyhow::Result<Mailbox> {
+        let address: Address = self
+            .email
+            .parse()
+            .map_err(|e| anyhow::anyhow!("contact '{}' has an invalid address: {e}", self.email))?;
+        Ok(Mailbox::new(self.name.clone(), address))
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AddressBook {
+    #[serde(default)]
+    pub contacts: Vec<Contact>,
+    /// Group name -> members (aliases or addresses)
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+impl AddressBook {
+    fn path() -> anyhow::Result<PathBuf> {
+        Ok(ConfigManager::data_dir()?.join("contacts.toml"))
+    }
+
+    /// Read the address book, an address book that doesn't exist yet is empty
+    pub fn load() -> anyhow::Result<Self> {
+        let path = Self::path()?;
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow::anyhow!("failed to read address book '{}': {e}", path.display()))
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        fs::write(Self::path()?, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Find a contact by alias or address
+    pub fn find(&self, key: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|contact| {
+            contact.alias.as_deref() == Some(key) || contact.email.eq_ignore_ascii_case(key)
+        })
+    }
+
+    /// Add `contact`, replacing an existing contact with the same address.
+    /// Returns `true` if an existing contact was replaced.
+    pub fn add(&mut self, contact: Contact) -> anyhow::Result<bool> {
+        contact.mailbox()?;
+
+        if let Some(alias) = &contact.alias {
+            if let Some(other) = self.find(alias).filter(|other| !other.email.eq_ignore_ascii_case(&contact.email)) {
+                anyhow::bail!("alias '{alias}' is already used by '{}'", other.email);
+            }
+        }
+
+        let existing = self
+            .contacts
+            .iter_mut()
+            .find(|existing| existing.email.eq_ignore_ascii_case(&contact.email));
+
+        Ok(match existing {
+            Some(existing) => {
+                *existing = contact;
+                true
+            }
+            None => {
+                self.contacts.push(contact);
+                false
+            }
+        })
+    }
+
+    /// Remove the contact with the alias or address `key`, also from all groups.
+    /// If `key` is a group name prefixed with '@', the group is removed.
+    pub fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        if let Some(group) = key.strip_prefix('@') {
+            return match self.groups.remove(group) {
+                Some(_) => Ok(()),
+                None => Err(anyhow::anyhow!("no group named '{group}'")),
+            };
+        }
+
+        let contact = self.find(key).cloned().ok_or(anyhow::anyhow!("no contact '{key}'"))?;
+        self.contacts.retain(|other| !other.email.eq_ignore_ascii_case(&contact.email));
+
+        for members in self.groups.values_mut() {
+            members.retain(|member| {
+                !member.eq_ignore_ascii_case(&contact.email) && contact.alias.as_deref() != Some(member)
+            });
+        }
+        self.groups.retain(|_, members| !members.is_empty());
+
+        Ok(())
+    }
+
+    /// Merge imported contacts into the address book.
+    /// Every address is checked with `ConfigManager::email_validator`, invalid entries and entries without an address are reported
+    /// in the summary. Contacts whose address is already known only fill in missing fields.
+    pub fn import(&mut self, imported: Vec<ImportedContact>) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+
+        for ImportedContact { location, contact } in imported {
+            if contact.email.is_empty() {
+                let name = contact.name.map(|name| format!(" ('{name}')")).unwrap_or_default();
+                summary.invalid.push(format!("{location}{name}: no e-mail address"));
+                continue;
+            }
+            match ConfigManager::email_validator(&contact.email) {
+                Ok(Validation::Valid) => {}
+                Ok(Validation::Invalid(ErrorMessage::Custom(reason))) => {
+                    summary.invalid.push(format!("{location}: '{}': {reason}", contact.email));
+                    continue;
+                }
+                _ => {
+                    summary.invalid.push(format!("{location}: '{}': invalid address", contact.email));
+                    continue;
+                }
+            }
+
+            let alias_taken = contact.alias.as_deref().and_then(|alias| self.find(alias)).is_some_and(|other| {
+                !other.email.eq_ignore_ascii_case(&contact.email)
+            });
+            let alias = if alias_taken { None } else { contact.alias };
+
+            match self
+                .contacts
+                .iter_mut()
+                .find(|existing| existing.email.eq_ignore_ascii_case(&contact.email))
+            {
+                Some(existing) => {
+                    existing.name = existing.name.take().or(contact.name);
+                    existing.alias = existing.alias.take().or(alias);
+                    summary.merged += 1;
+                }
+                None => {
+                    self.contacts.push(Contact {
+                        email: contact.email,
+                        name: contact.name,
+                        alias,
+                    });
+                    summary.added += 1;
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Add `member` (an alias or address) to `group`
+    pub fn add_to_group(&mut self, group: &str, member: &str) {
+        let members = self.groups.entry(group.to_string()).or_default();
+        if !members.iter().any(|other| other == member) {
+            members.push(member.to_string());
+        }
+    }
+
+    /// Resolve a single alias, address or `Name <address>` into a mailbox.
+    /// Known addresses without a display name get the name from the address book.
+    fn resolve_one(&self, key: &str) -> anyhow::Result<Mailbox> {
+        if let Some(contact) = self.find(key) {
+            return contact.mailbox();
+        }
+
+        let mailbox: Mailbox = key.parse().map_err(|err| {
+            anyhow::anyhow!("'{key}' is neither a contact nor a valid address: {err}")
+        })?;
+
+        match (&mailbox.name, self.find(mailbox.email.as_ref())) {
+            (None, Some(contact)) => contact.mailbox(),
+            _ => Ok(mailbox),
+        }
+    }
+
+    /// Resolve a comma separated list of aliases, `@groups` and addresses
+    pub fn resolve(&self, to: &str) -> anyhow::Result<Vec<Mailbox>> {
+        let mut mailboxes: Vec<Mailbox> = Vec::new();
+
+        for key in split_list(to) {
+            let resolved = match key.strip_prefix('@') {
+                Some(group) => self
+                    .groups
+                    .get(group)
+                    .ok_or(anyhow::anyhow!("no group named '{group}'"))?
+                    .iter()
+                    .map(|member| self.resolve_one(member))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                None => vec![self.resolve_one(key)?],
+            };
+
+            // Skip addresses that are in several groups
+            for mailbox in resolved {
+                if !mailboxes.iter().any(|other| other.email == mailbox.email) {
+                    mailboxes.push(mailbox);
+                }
+            }
+        }
+
+        if mailboxes.is_empty() {
+            anyhow::bail!("no recipients given");
+        }
+
+        Ok(mailboxes)
+    }
+
+    /// Print all contacts and groups
+    pub fn print(&self) {
+        for contact in &self.contacts {
+            let alias = contact.alias.as_deref().map(|alias| format!("[{alias}] ")).unwrap_or_default();
+            match &contact.name {
+                Some(name) => println!("{}{name} <{}>", alias.bold(), contact.email),
+                None => println!("{}{}", alias.bold(), contact.email),
+            }
+        }
+
+        for (group, members) in &self.groups {
+            println!("{} {}", format!("@{group}:").bold(), members.join(", "));
+        }
+    }
+}
+
+/// Byte offsets of the commas that separate a list of recipients.
+/// Commas in quoted display names like `"Doe, John" <john@example.com>` don't count.
+fn separators(list: &str) -> Vec<usize> {
+    let mut commas = Vec::new();
+    let (mut quoted, mut escaped) = (false, false);
+
+    for (i, c) in list.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => commas.push(i),
+            _ => {}
+        }
+    }
+
+    commas
+}
+
+/// The trimmed, non-empty entries of a comma separated list of recipients
+fn split_list(list: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+
+    for comma in separators(list) {
+        entries.push(&list[start..comma]);
+        start = comma + 1;
+    }
+    entries.push(&list[start..]);
+
+    entries.into_iter().map(str::trim).filter(|entry| !entry.is_empty()).collect()
+}
+
+/// Outcome of `AddressBook::import`
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub added: usize,
+    /// Duplicates of contacts already in the address book (or earlier in the same file)
+    pub merged: usize,
+    /// Descriptions of the entries that were not imported
+    pub invalid: Vec<String>,
+}
+
+/// Fuzzy autocompletion of aliases, groups and addresses for the recipient prompt.
+/// Only the last entry of a comma separated list is completed.
+#[derive(Clone)]
+pub struct RecipientCompleter {
+    candidates: Vec<String>,
+}
+
+impl RecipientCompleter {
+    pub fn new(book: &AddressBook) -> Self {
+        let mut candidates = Vec::new();
+
+        for contact in &book.contacts {
+            candidates.extend(contact.alias.clone());
+            candidates.push(contact.email.clone());
+        }
+        candidates.extend(book.groups.keys().map(|group| format!("@{group}")));
+
+        Self { candidates }
+    }
+
+    fn split_last(input: &str) -> (&str, &str) {
+        match separators(input).last() {
+            Some(&comma) => (&input[..comma], input[comma + 1..].trim_start()),
+            None => ("", input),
+        }
+    }
+}
+
+impl Autocomplete for RecipientCompleter {
+    fn get_suggestions(&mut self, input: &str) -> Result<Vec<String>, CustomUserError> {
+        let (head, last) = Self::split_last(input);
+        if last.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let matcher = SkimMatcherV2::default();
+        let mut scored: Vec<(i64, &String)> = self
+            .candidates
+            .iter()
+            .filter_map(|candidate| matcher.fuzzy_match(candidate, last).map(|score| (score, candidate)))
+            .collect();
+        scored.sort_by_key(|(score, _)| Reverse(*score));
+
+        Ok(scored
+            .into_iter()
+            .take(8)
+            .map(|(_, candidate)| {
+                if head.is_empty() {
+                    candidate.clone()
+                } else {
+                    format!("{head}, {candidate}")
+                }
+            })
+            .collect())
+    }
+
+    fn get_completion(
+        &mut self,
+        input: &str,
+        highlighted_suggestion: Option<String>,
+    ) -> Result<Replacement, CustomUserError> {
+        Ok(match highlighted_suggestion {
+            Some(suggestion) => Some(suggestion),
+            None => self.get_suggestions(input)?.into_iter().next(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book() -> AddressBook {
+        let contact = |email: &str, name: Option<&str>, alias: Option<&str>| Contact {
+            email: email.to_string(),
+            name: name.map(str::to_string),
+            alias: alias.map(str::to_string),
+        };
+
+        AddressBook {
+            contacts: vec![
+                contact("ann@example.com", Some("Ann Lee"), Some("ann")),
+                contact("bob@example.com", None, Some("bob")),
+                contact("carl@example.com", Some("Doe, Carl"), None),
+            ],
+            groups: BTreeMap::from([
+                ("oncall".to_string(), vec!["ann".to_string(), "carl@example.com".to_string()]),
+                ("team".to_string(), vec!["bob".to_string(), "ANN@example.com".to_string()]),
+                ("broken".to_string(), vec!["ann".to_string(), "nobody".to_string()]),
+            ]),
+        }
+    }
+
+    fn addresses(mailboxes: &[Mailbox]) -> Vec<String> {
+        mailboxes.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn commas_in_quoted_names_dont_split_the_list() {
+        let list = r#""Doe, John" <john@example.com>, ann ,, "Say \"hi, there\"" <hi@example.com>,"#;
+
+        assert_eq!(separators(list), [30, 36, 37, 75]);
+        assert_eq!(
+            split_list(list),
+            [r#""Doe, John" <john@example.com>"#, "ann", r#""Say \"hi, there\"" <hi@example.com>"#]
+        );
+        assert_eq!(RecipientCompleter::split_last(r#"ann, "Doe, Jo"#), ("ann", r#""Doe, Jo"#));
+    }
+
+    #[test]
+    fn quoted_names_resolve_to_one_recipient() {
+        let resolved = book().resolve(r#""Doe, John" <john@example.com>, ann"#).unwrap();
+
+        assert_eq!(addresses(&resolved), [r#""Doe, John" <john@example.com>"#, "Ann Lee <ann@example.com>"]);
+    }
+
+    #[test]
+    fn groups_expand_without_duplicates() {
+        let resolved = book().resolve("@oncall, bob, @team, carl@example.com").unwrap();
+
+        assert_eq!(
+            addresses(&resolved),
+            ["Ann Lee <ann@example.com>", r#""Doe, Carl" <carl@example.com>"#, "bob@example.com"]
+        );
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        let book = book();
+        let error = |to: &str| book.resolve(to).unwrap_err().to_string();
+
+        assert!(error("ann, nobody").starts_with("'nobody' is neither a contact nor a valid address"));
+        assert!(error("@broken").starts_with("'nobody' is neither a contact nor a valid address"));
+        assert_eq!(error("@nogroup"), "no group named 'nogroup'");
+        assert_eq!(error(" , "), "no recipients given");
+    }
+}
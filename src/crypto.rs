@@ -3,7 +3,7 @@
 //! Nonces and Passwords are stored as byte arrays, because they are not guaranteed to be valid UTF-8  
 
 use aes_gcm::{
-    aead::{consts::{B0, B1}, Aead, AeadCore, KeyInit, OsRng}, aes::cipher::typenum::{UInt, UTerm}, Aes256Gcm, Nonce
+    aead::{consts::{B0, B1}, rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng}, aes::cipher::typenum::{UInt, UTerm}, Aes256Gcm, Nonce
 };
 
 /// Struct for en/de-cryption
@@ -38,8 +38,24 @@ impl Cipher {
 
     /// Return decrypted text from `text` and `nonce`
     pub fn decrypt<B: AsRef<[u8]>>(&self, data: B, nonce: &NonceArray) -> anyhow::Result<String> {
-        let bytes = self.0.decrypt(nonce, data.as_ref()).map_err(|e| anyhow::anyhow!("failed to decrypt: {e}"))?;
-        Ok(String::from_utf8(bytes)?)
+        Ok(String::from_utf8(self.decrypt_bytes(data, nonce)?)?)
     }
+
+    /// Like `decrypt`, for data that isn't necessarily UTF-8, e.g. a stored message
+    pub fn decrypt_bytes<B: AsRef<[u8]>>(&self, data: B, nonce: &NonceArray) -> anyhow::Result<Vec<u8>> {
+        self.0.decrypt(nonce, data.as_ref()).map_err(|e| anyhow::anyhow!("failed to decrypt: {e}"))
+    }
+}
+
+
+/// Random lowercase hex string of `len` bytes, used for ids of stored messages
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
+/// Compares a secret with what a client sent in a time that doesn't depend on where they differ
+pub fn secret_eq(secret: &str, given: &str) -> bool {
+    secret.len() == given.len() && openssl::memcmp::eq(secret.as_bytes(), given.as_bytes())
+}
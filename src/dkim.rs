@@ -0,0 +1,66 @@
+//! DKIM signing of outgoing mail.  
+//! The private key is read from the file configured in the `[dkim]` section of the profile:  
+//! a PKCS#1 PEM file for RSA, or the base64 encoded 32 byte secret for Ed25519.  
+
+use std::fs;
+
+use lettre::message::dkim::{
+    DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm, DkimSigningKey,
+};
+use lettre::message::header::HeaderName;
+use lettre::Message;
+
+use crate::config::{Canonicalization, ConfigManager, DkimAlgorithm, DkimSettings};
+
+impl From<Canonicalization> for DkimCanonicalizationType {
+    fn from(canonicalization: Canonicalization) -> Self {
+        match canonicalization {
+            Canonicalization::Simple => Self::Simple,
+            Canonicalization::Relaxed => Self::Relaxed,
+        }
+    }
+}
+
+impl DkimSettings {
+    fn dkim_config(&self) -> anyhow::Result<DkimConfig> {
+        let key = fs::read_to_string(&self.private_key)
+            .map_err(|e| anyhow::anyhow!("failed to read DKIM key '{}': {e}", self.private_key.display()))?;
+
+        let algorithm = match self.algorithm {
+            DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+            DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+        };
+        let key = DkimSigningKey::new(key.trim(), algorithm)
+            .map_err(|e| anyhow::anyhow!("invalid DKIM key '{}': {e}", self.private_key.display()))?;
+
+        let headers = self
+            .headers
+            .iter()
+            .map(|name| {
+                HeaderName::new_from_ascii(name.clone())
+                    .map_err(|_| anyhow::anyhow!("invalid header name '{name}' in the DKIM settings"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(DkimConfig::new(
+            self.selector.clone(),
+            self.domain.clone(),
+            key,
+            headers,
+            DkimCanonicalization {
+                header: self.header_canonicalization.into(),
+                body: self.body_canonicalization.into(),
+            },
+        ))
+    }
+}
+
+impl ConfigManager {
+    /// Add a `DKIM-Signature` header to `message`, if DKIM is configured
+    pub fn dkim_sign(&self, message: &mut Message) -> anyhow::Result<()> {
+        if let Some(dkim) = &self.config.dkim {
+            message.sign(&dkim.dkim_config()?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! Exit codes, one per class of error, so scripts can tell a wrong password from a wrong address.  
+//! Errors are classified by their source (SMTP replies, network errors) or marked with `Classify::class`  
+//! where the class is known, e.g. everything that goes wrong while reading the config.  
+
+use std::{error::Error, fmt};
+
+use lettre::transport::smtp;
+
+/// Listed in `--help`, keep in sync with `ErrorClass::code`
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  success, or the message was queued after a temporary failure
+  1  any other error
+  2  invalid command line arguments
+  3  the config is missing or can't be read
+  4  invalid input: recipients, addresses or attachments
+  5  the relay can't be reached: network, TLS, timeouts or a temporary (4xx) reply
+  6  the relay refused the login
+  7  the relay refused the message with a permanent (5xx) reply";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    Other,
+    Config,
+    Input,
+    Connection,
+    Auth,
+    Rejected,
+}
+
+impl ErrorClass {
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::Config => 3,
+            Self::Input => 4,
+            Self::Connection => 5,
+            Self::Auth => 6,
+            Self::Rejected => 7,
+        }
+    }
+
+    /// The name in the `category` of JSON events
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Other => "other",
+            Self::Config => "config",
+            Self::Input => "input",
+            Self::Connection => "connection",
+            Self::Auth => "auth",
+            Self::Rejected => "rejected",
+        }
+    }
+
+    /// The class of a failed SMTP command or connection
+    pub fn of_smtp(err: &smtp::Error) -> Self {
+        match err.status().map(|code| code.to_string()) {
+            // Authentication required, too weak, invalid credentials, encryption required
+            Some(code) if ["530", "534", "535", "538"].contains(&code.as_str()) => Self::Auth,
+            Some(_) if err.is_permanent() => Self::Rejected,
+            Some(_) => Self::Connection,
+            None if err.is_client() && err.to_string().contains("authentication") => Self::Auth,
+            None if err.is_client() => Self::Other,
+            None => Self::Connection,
+        }
+    }
+
+    /// The class of `err`, from the first cause that has one
+    pub fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(classified) = cause.downcast_ref::<Classified>() {
+                return classified.class;
+            }
+            if let Some(smtp_err) = cause.downcast_ref::<smtp::Error>() {
+                return Self::of_smtp(smtp_err);
+            }
+            if cause.is::<lettre::address::AddressError>() {
+                return Self::Input;
+            }
+        }
+
+        Self::Other
+    }
+}
+
+/// An error with a known class, shown just like the error it wraps
+#[derive(Debug)]
+struct Classified {
+    class: ErrorClass,
+    source: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Error for Classified {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+pub trait Classify<T> {
+    /// Mark the error as one of `class`, unless it already has a class
+    fn class(self, class: ErrorClass) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn class(self, class: ErrorClass) -> anyhow::Result<T> {
+        self.map_err(|err| {
+            let source = err.into();
+            match ErrorClass::of(&source) {
+                ErrorClass::Other => anyhow::Error::new(Classified { class, source }),
+                _ => source,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASSES: [(ErrorClass, i32, &str); 6] = [
+        (ErrorClass::Other, 1, "other"),
+        (ErrorClass::Config, 3, "config"),
+        (ErrorClass::Input, 4, "input"),
+        (ErrorClass::Connection, 5, "connection"),
+        (ErrorClass::Auth, 6, "auth"),
+        (ErrorClass::Rejected, 7, "rejected"),
+    ];
+
+    #[test]
+    fn codes() {
+        for (class, code, name) in CLASSES {
+            assert_eq!(class.code(), code, "{class:?}");
+            assert_eq!(class.name(), name, "{class:?}");
+        }
+    }
+
+    #[test]
+    fn codes_are_listed_in_help() {
+        for (class, code, _) in CLASSES {
+            assert!(EXIT_CODES.contains(&format!("\n  {code}  ")), "{class:?} is missing from EXIT_CODES");
+        }
+    }
+
+    #[test]
+    fn classified_errors() {
+        let err = Err::<(), _>(anyhow::anyhow!("no such file")).class(ErrorClass::Config).unwrap_err();
+        assert_eq!(ErrorClass::of(&err).code(), 3);
+
+        // The first class wins
+        let err = Err::<(), _>(err).class(ErrorClass::Input).unwrap_err();
+        assert_eq!(ErrorClass::of(&err).code(), 3);
+
+        let err = anyhow::Error::new("nobody".parse::<lettre::Address>().unwrap_err());
+        assert_eq!(ErrorClass::of(&err).code(), 4);
+
+        assert_eq!(ErrorClass::of(&anyhow::anyhow!("something else")).code(), 1);
+    }
+}
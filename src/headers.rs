@@ -0,0 +1,142 @@
+//! Extra headers: `--header`, the default headers of a profile and the optional standard headers  
+//! (priority, `List-Unsubscribe`, read receipts).  
+//! Header names and values are checked against RFC 5322 before they are added.  
+
+use clap::ValueEnum;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::Message;
+
+/// Headers mailr sets itself, these can't be passed with `--header`
+const RESERVED: [&str; 12] = [
+    "From",
+    "To",
+    "Cc",
+    "Bcc",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+/// Check `name` and `value` against the RFC 5322 header syntax.
+/// Names are printable ASCII without ':', values may not contain line breaks or other control characters.
+pub fn validate(name: &str, value: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("the header name is empty");
+    }
+    if let Some(c) = name.chars().find(|c| !c.is_ascii_graphic() || *c == ':') {
+        anyhow::bail!("invalid character {c:?} in header name '{name}'");
+    }
+    if name.len() > 76 {
+        anyhow::bail!("header name '{name}' is longer than 76 characters");
+    }
+    if let Some(c) = value.chars().find(|c| c.is_control() && *c != '\t') {
+        anyhow::bail!("invalid character {c:?} in the value of header '{name}'");
+    }
+    if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        anyhow::bail!("'{name}' is set by mailr itself and can't be overridden");
+    }
+
+    Ok(())
+}
+
+/// Parse `Name: value` for `--header`
+pub fn parse_header(s: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or(anyhow::anyhow!("expected \"Name: value\", got '{s}'"))?;
+    let (name, value) = (name.trim(), value.trim());
+
+    validate(name, value)?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Add (or replace) the header `name` of `message`
+pub fn set(message: &mut Message, name: &str, value: &str) -> anyhow::Result<()> {
+    validate(name, value)?;
+
+    let name = HeaderName::new_from_ascii(name.to_string())
+        .map_err(|_| anyhow::anyhow!("invalid header name '{name}'"))?;
+    message.headers_mut().insert_raw(HeaderValue::new(name, value.to_string()));
+
+    Ok(())
+}
+
+/// Set the priority headers understood by the common mail clients
+pub fn set_priority(message: &mut Message, priority: Priority) -> anyhow::Result<()> {
+    let (x_priority, importance, priority) = match priority {
+        Priority::High => ("1 (Highest)", "high", "urgent"),
+        Priority::Normal => ("3 (Normal)", "normal", "normal"),
+        Priority::Low => ("5 (Lowest)", "low", "non-urgent"),
+    };
+
+    set(message, "X-Priority", x_priority)?;
+    set(message, "Importance", importance)?;
+    set(message, "Priority", priority)
+}
+
+/// Format the targets of `List-Unsubscribe`, each URI enclosed in angle brackets
+pub fn list_unsubscribe(uris: &[String]) -> String {
+    uris.iter()
+        .map(|uri| match uri.starts_with('<') {
+            true => uri.clone(),
+            false => format!("<{uri}>"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_headers_are_accepted() {
+        assert!(validate("X-Mailer-Campaign", "spring 2026\tbatch 1").is_ok());
+        assert_eq!(
+            parse_header("X-Ticket:  4711 ").unwrap(),
+            ("X-Ticket".to_string(), "4711".to_string())
+        );
+    }
+
+    #[test]
+    fn reserved_headers_are_rejected() {
+        for name in ["From", "to", "SUBJECT", "Message-Id", "content-type"] {
+            let err = validate(name, "value").unwrap_err();
+            assert!(err.to_string().contains("set by mailr"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn line_breaks_in_values_are_rejected() {
+        for value in ["a\r\nBcc: victim@example.com", "a\nb", "a\rb", "a\0b"] {
+            assert!(validate("X-Test", value).is_err(), "{value:?} was accepted");
+        }
+        assert!(parse_header("X-Test: a\r\nBcc: victim@example.com").is_err());
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        for name in ["", "X Test", "X-Tést", "X-Ünïcode", "X:Test", "X-Test\r\n", &"X".repeat(77)] {
+            assert!(validate(name, "value").is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn list_unsubscribe_targets_are_bracketed() {
+        let uris = ["mailto:leave@example.com".to_string(), "<https://example.com/leave>".to_string()];
+
+        assert_eq!(list_unsubscribe(&uris), "<mailto:leave@example.com>, <https://example.com/leave>");
+    }
+}
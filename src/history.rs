@@ -0,0 +1,348 @@
+//! Append-only history of sent (and failed) mail.  
+//! Every delivery attempt adds one JSON line to `history.jsonl` in the data directory.  
+//! Bodies are only stored when enabled in the config, and then encrypted with `Cipher`.  
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use colored::Colorize;
+use lettre::{address::Envelope, transport::smtp::response::Response};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigManager, crypto::Cipher, mime, schedule, warning};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptedBody {
+    data: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Local>,
+    pub profile: String,
+    pub from: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub message_id: Option<String>,
+    pub sent: bool,
+    /// The SMTP response code, if the server answered
+    pub response_code: Option<u16>,
+    /// The server response, or the error if sending failed
+    pub response: String,
+    /// Size of the message in bytes
+    pub size: usize,
+    pub attachments: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<EncryptedBody>,
+}
+
+impl HistoryEntry {
+    /// The decrypted text body, if it was stored
+    pub fn decrypt_body(&self) -> anyhow::Result<Option<String>> {
+        match &self.body {
+            Some(body) => Ok(Some(Cipher::new().decrypt(&body.data, body.nonce.as_slice().into())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn path() -> anyhow::Result<PathBuf> {
+    Ok(ConfigManager::data_dir()?.join("history.jsonl"))
+}
+
+/// Append the outcome of delivering `raw` to the history
+pub fn record(
+    cf: &ConfigManager,
+    envelope: &Envelope,
+    raw: &[u8],
+    result: Result<&Response, &lettre::transport::smtp::Error>,
+) -> anyhow::Result<()> {
+    let settings = &cf.config.history;
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mail = mailparse::parse_mail(raw)?;
+
+    let body = match mime::body_of_type(&mail, "text/plain") {
+        Some(text) if settings.store_bodies => {
+            let mut data = Vec::new();
+            let nonce = Cipher::new().encrypt(text, &mut data)?;
+            Some(EncryptedBody {
+                data,
+                nonce: nonce.to_vec(),
+            })
+        }
+        _ => None,
+    };
+
+    let (response_code, response) = match result {
+        Ok(response) => (
+            response.code().to_string().parse().ok(),
+            response.message().collect::<Vec<_>>().join(" "),
+        ),
+        Err(err) => (err.status().and_then(|code| code.to_string().parse().ok()), err.to_string()),
+    };
+
+    let entry = HistoryEntry {
+        timestamp: Local::now(),
+        profile: cf.profile().to_string(),
+        from: mime::header(&mail, "From").unwrap_or_default(),
+        recipients: envelope.to().iter().map(|to| to.to_string()).collect(),
+        subject: mime::header(&mail, "Subject").unwrap_or_default(),
+        message_id: mime::header(&mail, "Message-ID"),
+        sent: result.is_ok(),
+        response_code,
+        response,
+        size: raw.len(),
+        attachments: mime::attachment_names(&mail),
+        body,
+    };
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path()?)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+    Ok(())
+}
+
+/// Parse `--since`: a date, a date & time, or a duration like `7d` meaning "7 days ago"
+pub fn parse_since(s: &str) -> anyhow::Result<DateTime<Local>> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Local
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+            .earliest()
+            .ok_or(anyhow::anyhow!("'{s}' does not exist in the local time zone"));
+    }
+    if let Ok(time) = schedule::parse_local_time(s) {
+        return Ok(time);
+    }
+
+    let ago: Duration = humantime::parse_duration(s)
+        .map_err(|_| anyhow::anyhow!("expected a date like '2026-10-01' or a duration like '7d', got '{s}'"))?;
+    Ok(Local::now() - ago)
+}
+
+#[derive(clap::Args, Debug)]
+pub struct HistoryArgs {
+    #[arg(long, value_parser(parse_since), help("only show mail sent after a date (2026-10-01) or within a duration (7d)"))]
+    pub since: Option<DateTime<Local>>,
+    #[arg(long, help("only show mail to recipients containing this text"))]
+    pub to: Option<String>,
+    #[arg(long, value_name("REGEX"), help("only show mail whose subject (or stored body) matches"))]
+    pub grep: Option<Regex>,
+    #[arg(long, action, help("print the matching entries as JSON"))]
+    pub json: bool,
+    #[arg(long, action, help("include the stored bodies"))]
+    pub bodies: bool,
+}
+
+impl HistoryArgs {
+    /// Whether `entry` passes the filters.
+    /// A stored body that can't be decrypted doesn't match `--grep`, the entry is only warned about.
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        if self.since.is_some_and(|since| entry.timestamp < since) {
+            return false;
+        }
+        if let Some(to) = &self.to {
+            let to = to.to_lowercase();
+            if !entry.recipients.iter().any(|recipient| recipient.to_lowercase().contains(&to)) {
+                return false;
+            }
+        }
+        if let Some(grep) = &self.grep {
+            if grep.is_match(&entry.subject) {
+                return true;
+            }
+            return match entry.decrypt_body() {
+                Ok(body) => body.is_some_and(|body| grep.is_match(&body)),
+                Err(err) => {
+                    warning(format!(
+                        "can't search the body of '{}' from {}: {err}",
+                        entry.subject,
+                        entry.timestamp.format("%Y-%m-%d %H:%M")
+                    ));
+                    false
+                }
+            };
+        }
+
+        true
+    }
+}
+
+/// All entries matching the filters in `args`, oldest first
+fn matching(args: &HistoryArgs) -> anyhow::Result<Vec<HistoryEntry>> {
+    let path = path()?;
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+
+    for (i, line) in fs::read_to_string(&path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: HistoryEntry = serde_json::from_str(line)
+            .map_err(|e| anyhow::anyhow!("failed to read line {} of '{}': {e}", i + 1, path.display()))?;
+
+        if args.matches(&entry) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// `mailr history`
+pub fn show(args: &HistoryArgs) -> anyhow::Result<()> {
+    let entries = matching(args)?;
+
+    if args.json {
+        let values = entries
+            .iter()
+            .map(|entry| {
+                let mut value = serde_json::to_value(entry)?;
+                value["body"] = match args.bodies {
+                    true => entry.decrypt_body()?.into(),
+                    false => serde_json::Value::Null,
+                };
+                Ok(value)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        println!("{}", serde_json::to_string_pretty(&values)?);
+        return Ok(());
+    }
+
+    for entry in &entries {
+        let status = match (entry.sent, entry.response_code) {
+            (true, Some(code)) => format!("sent {code}").green(),
+            (true, None) => "sent".green(),
+            (false, Some(code)) => format!("failed {code}").red(),
+            (false, None) => "failed".red(),
+        };
+
+        println!(
+            "{} [{status}] {} -> {}",
+            entry.timestamp.format("%Y-%m-%d %H:%M"),
+            entry.subject.as_str().bold(),
+            entry.recipients.join(", "),
+        );
+        println!(
+            "    {} via {}, {} bytes, id {}",
+            entry.from,
+            entry.profile,
+            entry.size,
+            entry.message_id.as_deref().unwrap_or("-")
+        );
+        if !entry.attachments.is_empty() {
+            println!("    attachments: {}", entry.attachments.join(", "));
+        }
+        if !entry.sent {
+            println!("    error: {}", entry.response.dimmed());
+        }
+        if args.bodies {
+            if let Some(body) = entry.decrypt_body()? {
+                for line in body.lines() {
+                    println!("    | {line}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(days_ago: i64, recipients: &[&str], subject: &str, body: Option<EncryptedBody>) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: Local::now() - chrono::Duration::days(days_ago),
+            profile: "default".to_string(),
+            from: "me@example.com".to_string(),
+            recipients: recipients.iter().map(|to| to.to_string()).collect(),
+            subject: subject.to_string(),
+            message_id: None,
+            sent: true,
+            response_code: Some(250),
+            response: "queued".to_string(),
+            size: 100,
+            attachments: Vec::new(),
+            body,
+        }
+    }
+
+    fn encrypted(text: &str) -> EncryptedBody {
+        let mut data = Vec::new();
+        let nonce = Cipher::new().encrypt(text, &mut data).unwrap();
+        EncryptedBody {
+            data,
+            nonce: nonce.to_vec(),
+        }
+    }
+
+    fn filters(since: Option<&str>, to: Option<&str>, grep: Option<&str>) -> HistoryArgs {
+        HistoryArgs {
+            since: since.map(|since| parse_since(since).unwrap()),
+            to: to.map(str::to_string),
+            grep: grep.map(|grep| Regex::new(grep).unwrap()),
+            json: false,
+            bodies: false,
+        }
+    }
+
+    #[test]
+    fn since_takes_dates_times_and_durations() {
+        let midnight = NaiveDate::from_ymd_opt(2026, 10, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        assert_eq!(parse_since("2026-10-01").unwrap().naive_local(), midnight);
+        assert_eq!(parse_since("2026-10-01 09:30").unwrap().naive_local(), midnight + chrono::Duration::minutes(570));
+
+        let week_ago = Local::now() - chrono::Duration::days(7);
+        let since = parse_since("7d").unwrap();
+        assert!((since - week_ago).num_seconds().abs() < 5, "{since}");
+
+        assert_eq!(
+            parse_since("last week").unwrap_err().to_string(),
+            "expected a date like '2026-10-01' or a duration like '7d', got 'last week'"
+        );
+    }
+
+    #[test]
+    fn filters_combine() {
+        let old = entry(10, &["Ann <ann@example.com>"], "Report", None);
+        let new = entry(1, &["bob@example.com", "ANN@example.com"], "Invoice 42", Some(encrypted("total: 100 EUR")));
+
+        assert!(filters(None, None, None).matches(&old));
+        assert!(!filters(Some("7d"), None, None).matches(&old));
+        assert!(filters(Some("7d"), None, None).matches(&new));
+
+        assert!(filters(None, Some("ann@"), None).matches(&old));
+        assert!(filters(None, Some("Ann@Example"), None).matches(&new));
+        assert!(!filters(None, Some("carl"), None).matches(&new));
+
+        assert!(filters(None, None, Some("^Invoice \\d+$")).matches(&new));
+        assert!(filters(None, None, Some("\\d+ EUR")).matches(&new));
+        assert!(!filters(None, None, Some("EUR")).matches(&old));
+        assert!(!filters(Some("7d"), Some("ann"), Some("Report")).matches(&old));
+    }
+
+    #[test]
+    fn undecryptable_bodies_dont_match() {
+        let mut body = encrypted("total: 100 EUR");
+        body.data[0] ^= 1;
+        let broken = entry(1, &["bob@example.com"], "Invoice 42", Some(body));
+
+        assert!(!filters(None, None, Some("EUR")).matches(&broken));
+        assert!(filters(None, None, Some("Invoice")).matches(&broken));
+    }
+}
@@ -0,0 +1,399 @@
+//! `mailr serve-http`: a local HTTP API for sending mail, for tools that shouldn't hold the SMTP login.  
+//! `POST /send` takes a JSON message, `GET /queue` lists the outbox and `GET /health` is for monitoring.  
+//! Every request but `/health` needs the bearer token from the `[http]` section, errors are JSON too.  
+
+use std::{io::Read, thread};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{config::ConfigManager, crypto, info, mail::SendMail, queue, warning, Args};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8025";
+
+/// Largest accepted request body, attachments are base64 encoded in it
+const MAX_BODY: u64 = 50 * 1024 * 1024;
+
+/// Requests handled at the same time
+const WORKERS: usize = 4;
+
+#[derive(clap::Args, Debug)]
+pub struct ServeHttpArgs {
+    #[arg(short, long, value_name("ADDR:PORT"), help("where to listen, 127.0.0.1:8025 if not set here or in the config"))]
+    pub listen: Option<String>,
+    #[arg(long, action, exclusive(true), help("create a new bearer token (replacing the old one), print it and exit"))]
+    pub new_token: bool,
+}
+
+/// One or more recipients, as a string or a list of strings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Recipients {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Recipients {
+    fn joined(&self) -> String {
+        match self {
+            Self::One(to) => to.clone(),
+            Self::Many(to) => to.join(", "),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AttachmentRequest {
+    filename: String,
+    /// The content, base64 encoded
+    data: String,
+}
+
+/// The body of `POST /send`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SendRequest {
+    to: Recipients,
+    cc: Option<Recipients>,
+    /// One of the configured identities
+    from: Option<String>,
+    subject: String,
+    #[serde(default)]
+    text: String,
+    html: Option<String>,
+    #[serde(default)]
+    attachments: Vec<AttachmentRequest>,
+    /// Put the message in the outbox instead of sending it now
+    #[serde(default)]
+    queue: bool,
+    #[serde(default)]
+    no_signature: bool,
+}
+
+/// A failed request, sent as `{"error": {"code": ..., "message": ...}}`
+struct ApiError {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+type Reply = Result<(u16, Value), ApiError>;
+
+fn respond(request: Request, reply: Reply) {
+    let (status, body) = match reply {
+        Ok(reply) => reply,
+        Err(err) => (err.status, json!({ "error": { "code": err.code, "message": err.message } })),
+    };
+
+    let content_type = Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+
+    if let Err(err) = request.respond(response) {
+        warning(format!("failed to answer a request: {err}"));
+    }
+}
+
+fn authorize(cf: &ConfigManager, request: &Request) -> Result<(), ApiError> {
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if cf.config.http.check_token(token.trim()) => Ok(()),
+        Some(_) => Err(ApiError::new(401, "unauthorized", "invalid bearer token")),
+        None => Err(ApiError::new(401, "unauthorized", "missing bearer token")),
+    }
+}
+
+fn list_queue() -> Reply {
+    let entries = queue::Outbox::open()
+        .and_then(|outbox| outbox.list())
+        .map_err(|e| ApiError::new(500, "internal", e))?;
+
+    let entries: Vec<Value> = entries
+        .into_iter()
+        .map(|entry| {
+            json!({
+                "id": entry.id,
+                "subject": entry.subject,
+                "to": entry.envelope.to().iter().map(|to| to.to_string()).collect::<Vec<_>>(),
+                "created": entry.created,
+                "attempts": entry.attempts,
+                "next_attempt": entry.next_attempt,
+                "last_error": entry.last_error,
+                "failed": entry.failed,
+            })
+        })
+        .collect();
+
+    Ok((200, json!({ "messages": entries })))
+}
+
+fn send(cf: &ConfigManager, body: &[u8]) -> Reply {
+    let request: SendRequest =
+        serde_json::from_slice(body).map_err(|e| ApiError::new(400, "invalid_request", e))?;
+
+    let mut attach_data = Vec::new();
+    for attachment in request.attachments {
+        let data = BASE64.decode(attachment.data.trim()).map_err(|e| {
+            ApiError::new(400, "invalid_request", format!("attachment '{}' isn't valid base64: {e}", attachment.filename))
+        })?;
+        attach_data.push((attachment.filename, data));
+    }
+
+    let args = Args {
+        from: request.from,
+        to: request.to.joined(),
+        cc: request.cc.map(|cc| cc.joined()).unwrap_or_default(),
+        subject: request.subject,
+        msg: request.text,
+        html: request.html,
+        attach_data,
+        no_signature: request.no_signature,
+        ..Default::default()
+    };
+    if args.to.trim().is_empty() {
+        return Err(ApiError::new(400, "invalid_request", "no recipients"));
+    }
+
+    let mut message = cf.message(&args).map_err(|e| ApiError::new(400, "invalid_message", e))?;
+    cf.sign(&mut message).map_err(|e| ApiError::new(500, "signing_failed", e))?;
+    let message_id = message.headers().get_raw("Message-ID").map(str::to_string);
+
+    let push = |err: Option<&anyhow::Error>| {
+        queue::Outbox::open()
+            .and_then(|outbox| outbox.push(&message, &args.subject, err, &cf.config.queue))
+            .map_err(|e| ApiError::new(500, "internal", e))
+    };
+
+    if request.queue {
+        let id = push(None)?;
+        return Ok((202, json!({ "status": "queued", "id": id, "message_id": message_id })));
+    }
+
+    match cf.deliver_raw(message.envelope(), &message.formatted()) {
+        Ok(response) => Ok((
+            200,
+            json!({
+                "status": "sent",
+                "message_id": message_id,
+                "response": {
+                    "code": response.code().to_string(),
+                    "message": response.message().collect::<Vec<_>>().join(" "),
+                },
+            }),
+        )),
+        Err(err) if queue::is_retryable(&err) => {
+            let id = push(Some(&err))?;
+            Ok((
+                202,
+                json!({ "status": "queued", "id": id, "message_id": message_id, "error": err.to_string() }),
+            ))
+        }
+        Err(err) => Err(ApiError::new(502, "send_failed", err)),
+    }
+}
+
+fn handle(cf: &ConfigManager, mut request: Request) {
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+
+    let method = request.method().clone();
+    let reply = match (path.as_str(), &method) {
+        ("/health", Method::Get) => Ok((200, json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") }))),
+        ("/queue", Method::Get) => authorize(cf, &request).and_then(|_| list_queue()),
+        ("/send", Method::Post) => authorize(cf, &request).and_then(|_| {
+            let mut body = Vec::new();
+            request
+                .as_reader()
+                .take(MAX_BODY + 1)
+                .read_to_end(&mut body)
+                .map_err(|e| ApiError::new(400, "invalid_request", e))?;
+            if body.len() as u64 > MAX_BODY {
+                return Err(ApiError::new(413, "payload_too_large", format!("the body is over {MAX_BODY} bytes")));
+            }
+            send(cf, &body)
+        }),
+        ("/health" | "/queue" | "/send", _) => {
+            Err(ApiError::new(405, "method_not_allowed", format!("{method} isn't allowed on {path}")))
+        }
+        _ => Err(ApiError::new(404, "not_found", format!("no such endpoint '{path}'"))),
+    };
+
+    match &reply {
+        Ok((status, _)) => info(format!("{method} {path}: {status}")),
+        Err(err) => warning(format!("{method} {path}: {} {}", err.status, err.message)),
+    }
+    respond(request, reply);
+}
+
+fn new_token(cf: &mut ConfigManager) -> anyhow::Result<()> {
+    let token = crypto::random_hex(32);
+    cf.config.http.set_token(&token)?;
+    cf.update()?;
+
+    info("created a new token, the old one doesn't work anymore:");
+    println!("{token}");
+    Ok(())
+}
+
+/// `mailr serve-http`, runs until it is stopped
+pub fn serve_http(cf: &mut ConfigManager, args: &ServeHttpArgs) -> anyhow::Result<()> {
+    if args.new_token {
+        return new_token(cf);
+    }
+    if !cf.config.http.has_token() {
+        anyhow::bail!("no token configured, create one with `{} serve-http --new-token`", env!("CARGO_PKG_NAME"));
+    }
+
+    let listen = args
+        .listen
+        .clone()
+        .or(cf.config.http.listen.clone())
+        .unwrap_or(DEFAULT_LISTEN.to_string());
+    let server = Server::http(&listen).map_err(|e| anyhow::anyhow!("failed to listen on '{listen}': {e}"))?;
+
+    info(format!("accepting requests on http://{listen}, press CTRL-C to stop"));
+
+    let cf = &*cf;
+    let server = &server;
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(move || loop {
+                match server.recv() {
+                    Ok(request) => handle(cf, request),
+                    Err(err) => warning(format!("failed to receive a request: {err}")),
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpStream},
+    };
+
+    use super::*;
+    use crate::test_support::config;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn server_config() -> ConfigManager {
+        let mut cf = config(25);
+        cf.config.http.set_token(TOKEN).unwrap();
+        cf
+    }
+
+    /// `method path` with `body`, and the bearer `token` if there is one
+    fn request(method: &str, path: &str, token: Option<&str>, body: &[u8]) -> Vec<u8> {
+        let authorization = token.map(|token| format!("Authorization: Bearer {token}\r\n")).unwrap_or_default();
+        let head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{authorization}Content-Length: {}\r\n\r\n",
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    /// Send `request` to `handle` over a local server, returns the status and the JSON body of the response
+    fn call(cf: &ConfigManager, request: Vec<u8>) -> (u16, Value) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = server.server_addr().to_ip().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(&request).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+        handle(cf, server.recv().unwrap());
+        let response = client.join().unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    /// The `{"error": {"code", "message"}}` of a failed request
+    fn error(cf: &ConfigManager, request: Vec<u8>) -> (u16, String, String) {
+        let (status, body) = call(cf, request);
+        let error = &body["error"];
+        assert_eq!(error.as_object().map(|error| error.len()), Some(2), "{body}");
+        (status, error["code"].as_str().unwrap().to_string(), error["message"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn health_needs_no_token() {
+        let (status, body) = call(&server_config(), request("GET", "/health", None, b""));
+
+        assert_eq!(status, 200);
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[test]
+    fn requests_without_the_token_are_unauthorized() {
+        let cf = server_config();
+
+        let (status, code, message) = error(&cf, request("GET", "/queue", None, b""));
+        assert_eq!((status, code.as_str(), message.as_str()), (401, "unauthorized", "missing bearer token"));
+
+        let (status, code, message) = error(&cf, request("POST", "/send", Some("0123456789abcdeF"), b"{}"));
+        assert_eq!((status, code.as_str(), message.as_str()), (401, "unauthorized", "invalid bearer token"));
+    }
+
+    #[test]
+    fn unknown_endpoints_and_methods_are_refused() {
+        let cf = server_config();
+
+        let (status, code, _) = error(&cf, request("GET", "/nothing", Some(TOKEN), b""));
+        assert_eq!((status, code.as_str()), (404, "not_found"));
+
+        let (status, code, message) = error(&cf, request("DELETE", "/send", Some(TOKEN), b""));
+        assert_eq!((status, code.as_str()), (405, "method_not_allowed"));
+        assert!(message.contains("DELETE"), "{message}");
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let body = vec![b' '; MAX_BODY as usize + 1];
+        let (status, code, _) = error(&server_config(), request("POST", "/send", Some(TOKEN), &body));
+
+        assert_eq!((status, code.as_str()), (413, "payload_too_large"));
+    }
+
+    #[test]
+    fn malformed_messages_are_invalid_requests() {
+        let cf = server_config();
+
+        let unknown_field = br#"{"to": "a@example.com", "subject": "hi", "bcc": "b@example.com"}"#;
+        let (status, code, message) = error(&cf, request("POST", "/send", Some(TOKEN), unknown_field));
+        assert_eq!((status, code.as_str()), (400, "invalid_request"));
+        assert!(message.contains("bcc"), "{message}");
+
+        let bad_base64 = br#"{"to": "a@example.com", "subject": "hi", "attachments": [{"filename": "a.txt", "data": "not base64!"}]}"#;
+        let (status, code, message) = error(&cf, request("POST", "/send", Some(TOKEN), bad_base64));
+        assert_eq!((status, code.as_str()), (400, "invalid_request"));
+        assert!(message.contains("a.txt"), "{message}");
+    }
+}
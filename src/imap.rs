@@ -0,0 +1,123 @@
+//! IMAP access to the account's mailbox.  
+//! Used to file sent mail in the server's Sent folder, so it shows up in other mail clients.  
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use imap::{types::Flag, Session};
+
+use crate::{config::ConfigManager, info};
+
+/// The connection to the IMAP server, with or without TLS
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+pub type ImapSession = Session<Box<dyn Stream>>;
+
+impl ConfigManager {
+    /// Log in to the configured IMAP server.
+    /// The SMTP login is used unless the IMAP settings have their own username.
+    pub fn imap_session(&self) -> anyhow::Result<ImapSession> {
+        let settings = self
+            .config
+            .imap
+            .as_ref()
+            .ok_or(anyhow::anyhow!("no IMAP server configured, add an [imap] section to the config"))?;
+
+        info(format!("connecting to {}:{}...", settings.host, settings.port));
+
+        let tcp = TcpStream::connect((settings.host.as_str(), settings.port))?;
+        // Plain TCP is only meant for local servers (or tests)
+        let stream: Box<dyn Stream> = if settings.tls {
+            let connector = native_tls::TlsConnector::new()?;
+            Box::new(connector.connect(&settings.host, tcp)?)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut client = imap::Client::new(stream);
+        client.read_greeting()?;
+
+        let username = settings.username.as_deref().unwrap_or(self.username());
+        client
+            .login(username, self.password())
+            .map_err(|(err, _)| anyhow::anyhow!("IMAP login as '{username}' failed: {err}"))
+    }
+
+    /// Append a delivered message to the Sent folder, marked as read.
+    /// The session is kept for the next message, so a batch logs in only once.
+    /// Does nothing if there is no IMAP server configured.
+    pub fn append_sent(&self, raw: &[u8]) -> anyhow::Result<()> {
+        let Some(settings) = &self.config.imap else {
+            return Ok(());
+        };
+        if !settings.append_sent {
+            return Ok(());
+        }
+
+        let mut session = self.sent_session.lock().unwrap();
+        let reused = session.is_some();
+        match self.append_with(&mut session, &settings.sent_folder, raw) {
+            // The server may have closed a session that was idle for a while
+            Err(_) if reused => self.append_with(&mut session, &settings.sent_folder, raw),
+            result => result,
+        }
+    }
+
+    /// Append `raw` over `session`, which is opened first if needed and dropped if it fails
+    fn append_with(&self, session: &mut Option<ImapSession>, folder: &str, raw: &[u8]) -> anyhow::Result<()> {
+        let current = match session {
+            Some(current) => current,
+            None => session.insert(self.imap_session()?),
+        };
+
+        if let Err(err) = current.append_with_flags(folder, raw, &[Flag::Seen]) {
+            *session = None;
+            anyhow::bail!("failed to append to '{folder}': {err}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{config_with, fake_imap};
+
+    fn config(port: u16) -> ConfigManager {
+        config_with(
+            25,
+            &format!(
+                r#"
+                [imap]
+                host = "127.0.0.1"
+                port = {port}
+                tls = false
+                append_sent = true
+                sent_folder = "Sent"
+                "#
+            ),
+        )
+    }
+
+    #[test]
+    fn append_sent_marks_seen_and_reuses_the_session() {
+        let (port, server) = fake_imap();
+        let cf = config(port);
+
+        let raw = b"Subject: test\r\n\r\nhello\r\n";
+        cf.append_sent(raw).unwrap();
+        cf.append_sent(raw).unwrap();
+        drop(cf);
+
+        let commands = server.join().unwrap();
+        let appends: Vec<_> = commands.iter().filter(|command| command.starts_with("APPEND")).collect();
+
+        assert_eq!(commands.iter().filter(|command| command.starts_with("LOGIN")).count(), 1);
+        assert_eq!(appends.len(), 2);
+        assert!(appends[0].starts_with(&format!("APPEND \"Sent\" (\\Seen) {{{}}}", raw.len())), "{}", appends[0]);
+    }
+}
@@ -0,0 +1,126 @@
+//! Read-only access to received mail over IMAP.  
+//! Folders are opened with EXAMINE and bodies fetched with BODY.PEEK, so nothing is marked as read.  
+
+use std::cmp::Reverse;
+
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use imap::types::Flag;
+use mailparse::MailHeaderMap;
+
+use crate::{config::ConfigManager, info, mime};
+
+#[derive(clap::Args, Debug)]
+pub struct InboxArgs {
+    #[arg(short, long, action, help("only list unread messages"))]
+    pub unread: bool,
+    #[arg(short, long, default_value_t = 20, help("number of messages to list, newest first"))]
+    pub limit: usize,
+    #[arg(short, long, default_value = "INBOX")]
+    pub folder: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ReadArgs {
+    /// UID of the message, as listed by `inbox`
+    pub uid: u32,
+    #[arg(short, long, default_value = "INBOX")]
+    pub folder: String,
+}
+
+/// `mailr inbox`
+pub fn list(cf: &ConfigManager, args: &InboxArgs) -> anyhow::Result<()> {
+    let mut session = cf.imap_session()?;
+    session
+        .examine(&args.folder)
+        .map_err(|e| anyhow::anyhow!("failed to open folder '{}': {e}", args.folder))?;
+
+    let mut uids: Vec<u32> = session
+        .uid_search(if args.unread { "UNSEEN" } else { "ALL" })?
+        .into_iter()
+        .collect();
+    uids.sort_unstable_by_key(|&uid| Reverse(uid));
+    uids.truncate(args.limit);
+
+    if uids.is_empty() {
+        info(match args.unread {
+            true => format!("no unread messages in '{}'", args.folder),
+            false => format!("'{}' is empty", args.folder),
+        });
+        session.logout()?;
+        return Ok(());
+    }
+
+    let uid_set: Vec<String> = uids.iter().map(u32::to_string).collect();
+    let fetches = session.uid_fetch(uid_set.join(","), "(UID FLAGS BODY.PEEK[HEADER])")?;
+
+    let mut messages: Vec<_> = fetches.iter().collect();
+    messages.sort_by_key(|fetch| Reverse(fetch.uid));
+
+    for fetch in messages {
+        let (headers, _) = mailparse::parse_headers(fetch.header().unwrap_or_default())?;
+        let header = |name: &str| headers.get_first_value(name).unwrap_or_default();
+
+        let seen = fetch.flags().contains(&Flag::Seen);
+        let subject = header("Subject");
+        let subject = if seen { subject.normal() } else { subject.bold() };
+
+        println!(
+            "{:>6} {} {:<30} {subject}",
+            fetch.uid.unwrap_or_default().to_string().dimmed(),
+            date(&header("Date")),
+            header("From"),
+        );
+    }
+
+    session.logout()?;
+    Ok(())
+}
+
+/// Short local form of a `Date` header, or the header itself if it can't be parsed
+fn date(header: &str) -> String {
+    match mailparse::dateparse(header) {
+        Ok(timestamp) => DateTime::from_timestamp(timestamp, 0)
+            .map(|date| date.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or(header.to_string()),
+        Err(_) => header.to_string(),
+    }
+}
+
+/// The raw message with `uid` in `folder`
+pub fn fetch(cf: &ConfigManager, folder: &str, uid: u32) -> anyhow::Result<Vec<u8>> {
+    let mut session = cf.imap_session()?;
+    session
+        .examine(folder)
+        .map_err(|e| anyhow::anyhow!("failed to open folder '{folder}': {e}"))?;
+
+    let fetches = session.uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")?;
+    let raw = fetches
+        .iter()
+        .find_map(|fetch| fetch.body())
+        .ok_or(anyhow::anyhow!("no message with UID {uid} in '{folder}'"))?
+        .to_vec();
+
+    session.logout()?;
+    Ok(raw)
+}
+
+/// `mailr read`
+pub fn read(cf: &ConfigManager, args: &ReadArgs) -> anyhow::Result<()> {
+    let raw = fetch(cf, &args.folder, args.uid)?;
+    let mail = mailparse::parse_mail(&raw)?;
+
+    for name in ["From", "To", "Cc", "Date", "Subject"] {
+        if let Some(value) = mime::header(&mail, name) {
+            println!("{} {value}", format!("{name}:").bold());
+        }
+    }
+    let attachments = mime::attachment_names(&mail);
+    if !attachments.is_empty() {
+        println!("{} {}", "Attachments:".bold(), attachments.join(", "));
+    }
+    println!();
+    println!("{}", mime::text_body(&mail).unwrap_or("(no text body)".to_string()).trim_end());
+
+    Ok(())
+}
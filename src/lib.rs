@@ -0,0 +1,197 @@
+//! Sends mail from the command line, and from services that embed it.  
+//! `send_or_queue` sends one message the way `mailr` does, with a `config::ConfigManager` and the `Args` of the command line.  
+//! With the `async` feature, `Batch` sends many messages over lettre's async transport on the caller's Tokio runtime.  
+
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use config::ConfigManager;
+use serde_json::json;
+
+use crate::log::{hint, info, warning};
+mod archive;
+mod batch;
+mod cli;
+pub mod config;
+mod contact_formats;
+mod contacts;
+mod crypto;
+mod dkim;
+mod exit;
+mod headers;
+mod history;
+mod http;
+mod imap;
+mod inbox;
+mod log;
+mod mail;
+mod merge;
+mod mime;
+mod pgp;
+mod queue;
+mod reply;
+mod run;
+mod schedule;
+mod serve;
+mod smime;
+mod templates;
+#[cfg(test)]
+mod test_support;
+mod transcript;
+mod watch;
+
+#[cfg(feature = "async")]
+pub use batch::{Batch, Delivery, Refused, Rejection, Summary};
+/// What the `mailr` binary runs
+#[doc(hidden)]
+pub use cli::main;
+// SendEmail trait
+use mail::SendMail;
+
+#[derive(clap::Args, Debug, Default)]
+pub struct Args {
+    #[arg(
+        short,
+        long,
+        action,
+        help("set the global/local user email & password")
+    )]
+    pub configure: bool,
+    #[arg(short, long, value_name("IDENTITY"), help("send as one of the configured identities instead of the default"))]
+    pub from: Option<String>,
+    #[arg(short, long, required_unless_present_any(["configure", "template"]), default_value = "")]
+    pub to: String,
+    #[arg(short, long, required_unless_present_any(["configure", "template"]), default_value = "")]
+    pub subject: String,
+    #[arg(short, long, required_unless_present_any(["configure", "template"]), default_value = "")]
+    pub msg: String,
+    #[arg(long, value_name("NAME"), help("fill in the message from a template"))]
+    pub template: Option<String>,
+    #[arg(long, value_name("KEY=VALUE"), value_parser(templates::parse_var), requires("template"), help("set a template variable, can be repeated"))]
+    pub var: Vec<(String, String)>,
+    #[arg(long, action, help("don't append the configured signature"))]
+    pub no_signature: bool,
+    #[arg(short = 'H', long, value_name("NAME: VALUE"), value_parser(headers::parse_header), help("add a header, can be repeated"))]
+    pub header: Vec<(String, String)>,
+    #[arg(long)]
+    pub priority: Option<headers::Priority>,
+    #[arg(long, value_name("URI"), help("add a List-Unsubscribe target (mailto: or https:), can be repeated"))]
+    pub list_unsubscribe: Vec<String>,
+    #[arg(long, action, help("ask the recipient for a read receipt"))]
+    pub read_receipt: bool,
+    #[arg(long, value_name("ADDRESS"), help("envelope sender, where bounces go (the Return-Path)"))]
+    pub envelope_from: Option<String>,
+    #[arg(long, value_name("DOMAIN"), help("domain of the generated Message-ID"))]
+    pub message_id_domain: Option<String>,
+    #[arg(long, action, help("sign the message with the configured PGP key (PGP/MIME)"))]
+    pub sign: bool,
+    #[arg(long, action, help("encrypt the message to the PGP keys of all recipients (PGP/MIME)"))]
+    pub encrypt: bool,
+    #[arg(long, action, help("use the configured S/MIME certificate for --sign and --encrypt instead of PGP"))]
+    pub smime: bool,
+    #[arg(long, action, help("put the message in the outbox instead of sending it now"))]
+    pub queue: bool,
+    #[arg(long, action, conflicts_with_all(["queue", "send_at", "delay"]), help("print the message as it would be sent (signed) instead of sending it"))]
+    pub dry_run: bool,
+    #[arg(long, value_name("TIME"), value_parser(schedule::parse_send_at), conflicts_with_all(["delay", "queue"]), help("send the message at a local time, e.g. \"2026-10-20 09:00\""))]
+    pub send_at: Option<DateTime<Local>>,
+    #[arg(long, value_name("DURATION"), value_parser(schedule::parse_delay), conflicts_with("queue"), help("send the message after a delay, e.g. 2h or \"1h 30m\""))]
+    pub delay: Option<Duration>,
+    /// Message-ID of the message this is a reply to
+    #[arg(skip)]
+    pub in_reply_to: Option<String>,
+    /// Message-IDs of the thread this reply belongs to, oldest first
+    #[arg(skip)]
+    pub references: Vec<String>,
+    /// A received message to attach as message/rfc822, for forwards
+    #[arg(skip)]
+    pub forwarded: Option<Vec<u8>>,
+    /// Carbon copy recipients, in the same form as `to`
+    #[arg(skip)]
+    pub cc: String,
+    /// An HTML body, sent as an alternative to `msg`
+    #[arg(skip)]
+    pub html: Option<String>,
+    /// Generated files to attach, as file name and content
+    #[arg(skip)]
+    pub attach_data: Vec<(String, Vec<u8>)>,
+}
+
+/// Send the mail described by `args`.
+/// If `--queue` was passed, or delivery failed with a temporary error, the message goes into the outbox.
+/// A message the server refused for good is kept there too, as failed, and the error is returned.
+pub fn send_or_queue(cf: &ConfigManager, args: &Args) -> anyhow::Result<()> {
+    // `--dry-run > message.eml` gets nothing but the message
+    if args.dry_run {
+        log::reserve_stdout();
+    }
+    let message = cf.message(args)?;
+
+    // `SendMail::send` signs on its own, messages that are stored for later are signed up front
+    let signed = || -> anyhow::Result<lettre::Message> {
+        let mut message = message.clone();
+        cf.sign(&mut message)?;
+        Ok(message)
+    };
+
+    if args.dry_run {
+        io::stdout().write_all(&signed()?.formatted())?;
+        info("dry run, the message was not sent");
+        return Ok(());
+    }
+
+    let due = match (args.send_at, args.delay) {
+        (Some(send_at), _) => Some(send_at.timestamp().max(0) as u64),
+        (_, Some(delay)) => Some(queue::now() + delay.as_secs()),
+        _ => None,
+    };
+
+    if let Some(due) = due {
+        let id = schedule::Schedule::open()?.push(&signed()?, &args.subject, due)?;
+        let at = DateTime::from_timestamp(due as i64, 0).unwrap_or_default().with_timezone(&Local);
+        info(format!("scheduled message as '{id}' for {}", at.format("%Y-%m-%d %H:%M")));
+        log::event("scheduled", json!({ "id": id, "due": at.to_rfc3339() }));
+        hint(format!("scheduled messages are sent by `{0} run-due` or `{0} daemon`", env!("CARGO_PKG_NAME")));
+        return Ok(());
+    }
+
+    if args.queue {
+        let id = queue::Outbox::open()?.push(&signed()?, &args.subject, None, &cf.config.queue)?;
+        info(format!("queued message as '{id}'"));
+        log::event("queued", json!({ "id": id }));
+        return Ok(());
+    }
+
+    match cf.send(&message) {
+        Ok(_) => {
+            info("Successfully sent Mail!");
+            Ok(())
+        }
+        Err(err) if queue::is_retryable(&err) => {
+            warning(format!("failed to send mail: {err}"));
+            let id = queue::Outbox::open()?.push(&signed()?, &args.subject, Some(&err), &cf.config.queue)?;
+            info(format!("queued message as '{id}', retry with `{} queue flush`", env!("CARGO_PKG_NAME")));
+            log::event("queued", json!({ "id": id, "error": err.to_string() }));
+            Ok(())
+        }
+        // Like `mailr serve` does with refused recipients, so the message isn't lost. Errors from before
+        // the server was asked, e.g. a missing key, would only happen again and aren't kept.
+        Err(err) if err.is::<lettre::transport::smtp::Error>() => {
+            let kept = signed().and_then(|message| {
+                queue::Outbox::open()?.push(&message, &args.subject, Some(&err), &cf.config.queue)
+            });
+            match kept {
+                Ok(id) => {
+                    info(format!("kept the message in the outbox as failed, see '{id}'"));
+                    log::event("queued", json!({ "id": id, "error": err.to_string(), "failed": true }));
+                }
+                Err(queue_err) => warning(format!("failed to keep the message in the outbox: {queue_err}")),
+            }
+            Err(err)
+        }
+        Err(err) => Err(err),
+    }
+}
@@ -1,26 +1,240 @@
 //! Logging errors, warnings, etc.  
-//! All of these functions output **colored** output  
+//! All of these functions output **colored** output, unless colors are turned off by `init`  
+//! With `--output json` every message is written as a JSON object instead, one per line.  
+//! Messages have a `Level`, `-q` hides info and hints, `-v` shows debug and `-vv` trace messages (the SMTP conversation).  
+//! With `--log-file` they are also appended to a file, without colors and with a timestamp.  
 
-use std::process;
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use chrono::Local;
+use clap::ValueEnum;
 use colored::Colorize;
+use serde_json::{json, Value};
+
+use crate::exit::ErrorClass;
+
+/// How messages are written, see `--output`
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Format {
+    /// Colored text for people
+    #[default]
+    Text,
+    /// One JSON event per line on stdout, for scripts
+    Json,
+}
+
+/// How important a message is, messages above the verbosity are not shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// The level for `-q` and the number of `-v`s
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Warning,
+            (false, 0) => Self::Info,
+            (false, 1) => Self::Debug,
+            (false, _) => Self::Trace,
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Self::Error,
+            1 => Self::Warning,
+            2 => Self::Info,
+            3 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Turn colors off for pipes, files and `NO_COLOR` (https://no-color.org)
+pub fn init() {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if no_color || !io::stdout().is_terminal() || !io::stderr().is_terminal() {
+        colored::control::set_override(false);
+    }
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+    if format == Format::Json {
+        colored::control::set_override(false);
+    }
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Write all messages and events to stderr from now on, stdout carries the output of the command (the message of `--dry-run`)
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+/// Prints `line` to stdout, or to stderr after `reserve_stdout`
+pub fn print(line: &str) {
+    match STDOUT_RESERVED.load(Ordering::Relaxed) {
+        true => eprintln!("{line}"),
+        false => println!("{line}"),
+    }
+}
+
+/// Whether messages of `level` are shown
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+/// Whether `progress` redraws a counter: stderr is a terminal, and neither `-q` nor `--output json` was passed
+pub fn progress_in_place() -> bool {
+    enabled(Level::Info) && !is_json() && io::stderr().is_terminal()
+}
+
+/// Redraws `line` in place on stderr, see `progress_in_place`
+pub fn progress(line: &str) {
+    let mut stderr = io::stderr();
+    let _ = write!(stderr, "\r\x1b[2K{line}");
+    let _ = stderr.flush();
+}
+
+/// Clears the line of `progress`
+pub fn clear_progress() {
+    progress("");
+}
+
+/// Also append every message to `path`.
+/// The file gets at least the info messages, so `-q --log-file` keeps the terminal quiet but not the file.
+pub fn set_file(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Appends "{time} {name}: {msg}" to the `--log-file`, if there is one
+fn to_file(level: Level, name: &str, msg: &str) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    if level > self::level().max(Level::Info) {
+        return;
+    }
+
+    let time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+    if let Ok(mut file) = file.lock() {
+        let _ = writeln!(file, "{time} {name}: {msg}");
+    }
+}
+
+/// Routes a message to the log file, and to the terminal with `print` (or as a JSON event) if `level` is shown
+fn log(level: Level, name: &str, msg: &str, print: fn(&str)) {
+    to_file(level, name, msg);
+    if !enabled(level) {
+        return;
+    }
 
-/// Logs "error: {cause}: {root}" to stderr
+    match is_json() {
+        true => event(name, json!({ "message": msg })),
+        false => print(msg),
+    }
+}
+
+/// Writes `{"event": name, "time": ..., ...fields}` to stdout (see `reserve_stdout`), only with `--output json`
+pub fn event(name: &str, fields: Value) {
+    if !is_json() {
+        return;
+    }
+
+    let mut object = match fields {
+        Value::Object(object) => object,
+        _ => Default::default(),
+    };
+    object.insert("event".to_string(), json!(name));
+    object.insert("time".to_string(), json!(Local::now().to_rfc3339()));
+
+    print(&Value::Object(object).to_string());
+}
+
+/// Logs "error: {cause}: {root}" to stderr, and exits with the code of its `ErrorClass`
 pub fn error<S: AsRef<str>>(cause: S, root: anyhow::Error) -> ! {
-    eprintln!("{}: {}: \"{root}\"", "error".bright_red().bold(), cause.as_ref().red());
-    process::exit(1)
+    let class = ErrorClass::of(&root);
+    to_file(Level::Error, "error", &format!("{}: \"{root}\"", cause.as_ref()));
+
+    match is_json() {
+        true => event(
+            "error",
+            json!({
+                "category": class.name(),
+                "exit_code": class.code(),
+                "message": cause.as_ref(),
+                "detail": root.to_string(),
+            }),
+        ),
+        false => eprintln!("{}: {}: \"{root}\"", "error".bright_red().bold(), cause.as_ref().red()),
+    }
+    process::exit(class.code())
 }
 
 /// Logs "warning: {msg}" to stderr
 pub fn warning<S: AsRef<str>>(msg: S) {
-    eprintln!("{}: {}", "warning".bright_yellow().bold(), msg.as_ref());
+    log(Level::Warning, "warning", msg.as_ref(), |msg| {
+        eprintln!("{}: {msg}", "warning".bright_yellow().bold())
+    });
 }
 
-/// Logs "info: {msg}" to stdout
+/// Logs "info: {msg}" to stdout (see `reserve_stdout`), unless `-q` is passed
 pub fn info<S: AsRef<str>>(msg: S) {
-    println!("{}: {}", "info".bright_green().bold(), msg.as_ref());
+    log(Level::Info, "info", msg.as_ref(), |msg| {
+        print(&format!("{}: {msg}", "info".bright_green().bold()))
+    });
 }
 
-/// Logs "hint: {msg}" to stdout
+/// Logs "hint: {msg}" to stdout (see `reserve_stdout`), unless `-q` is passed
 pub fn hint<S: AsRef<str>>(msg: S) {
-    println!("{}: {}", "hint".bright_magenta(), msg.as_ref().bold());
-}
\ No newline at end of file
+    log(Level::Info, "hint", msg.as_ref(), |msg| {
+        print(&format!("{}: {}", "hint".bright_magenta(), msg.bold()))
+    });
+}
+
+/// Logs "debug: {msg}" to stderr with `-v`
+pub fn debug<S: AsRef<str>>(msg: S) {
+    log(Level::Debug, "debug", msg.as_ref(), |msg| {
+        eprintln!("{}: {msg}", "debug".bright_blue())
+    });
+}
+
+/// Logs "trace: {msg}" to stderr with `-vv`
+pub fn trace<S: AsRef<str>>(msg: S) {
+    log(Level::Trace, "trace", msg.as_ref(), |msg| {
+        eprintln!("{}: {}", "trace".dimmed(), msg.dimmed())
+    });
+}
@@ -3,37 +3,176 @@
 //! and implements it.  
 
 use colored::Colorize;
-use lettre::message::Mailbox;
+use mailparse::MailHeaderMap;
+use serde_json::json;
+use lettre::address::Envelope;
 use lettre::message::MessageBuilder;
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+use lettre::message::Body;
+use lettre::message::Attachment;
+use lettre::message::MultiPart;
+use lettre::message::MultiPartBuilder;
+use lettre::message::SinglePart;
 use lettre::transport::smtp::client::Tls;
 use lettre::transport::smtp::client::TlsParameters;
+use lettre::transport::smtp;
+use lettre::transport::smtp::response::Response;
+use lettre::Address;
+use lettre::Message;
 use lettre::SmtpTransport;
 use lettre::Transport;
+#[cfg(feature = "async")]
+use lettre::{transport::smtp::PoolConfig, AsyncSmtpTransport, Tokio1Executor};
 
+use crate::archive;
 use crate::config;
+use crate::contacts::AddressBook;
+use crate::crypto;
+use crate::headers;
+use crate::history;
+use crate::exit::{Classify, ErrorClass};
 use crate::info;
+use crate::log;
+use crate::warning;
 use crate::Args;
 
 /// Something that can send mail when supplied with the recipient, message and subject via `args`
 pub trait SendMail {
-    /// Send an email to recipient specified in `args`
-    fn send(&self, args: &Args) -> anyhow::Result<()>;
+    /// Build the message for the recipient specified in `args`
+    fn message(&self, args: &Args) -> anyhow::Result<Message>;
+
+    /// Deliver an already formatted message to the recipients in `envelope`
+    fn deliver_raw(&self, envelope: &Envelope, raw: &[u8]) -> anyhow::Result<Response>;
+
+    /// Sign a message built by `SendMail::message`, e.g. with DKIM
+    fn sign(&self, message: &mut Message) -> anyhow::Result<()>;
+
+    /// Sign and send a message built by `SendMail::message`
+    fn send(&self, message: &Message) -> anyhow::Result<Response> {
+        let mut message = message.clone();
+        self.sign(&mut message)?;
+
+        info("sending message...");
+        self.deliver_raw(message.envelope(), &message.formatted())
+    }
 }
 
-impl SendMail for config::ConfigManager {
-    fn send(&self, args: &Args) -> anyhow::Result<()> {
-        // Username & Decrypted Password
-        let credentials = self.credentials();
+/// `bytes` with every bare LF turned into CRLF
+fn crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(bytes.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || bytes[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    converted
+}
+
+/// A received message as a `message/rfc822` attachment.
+/// RFC 2046 only allows 7bit or 8bit for it (base64 would hide it from mail clients), so it is sent as it is.
+fn forwarded_attachment(original: &[u8]) -> anyhow::Result<SinglePart> {
+    let original = crlf(original);
+
+    // What 8bit allows: lines of at most 998 bytes and no NULs, in any charset
+    let too_long = original.split(|&byte| byte == b'\n').any(|line| line.len() > 999);
+    if too_long || original.contains(&0) {
+        anyhow::bail!("the original message can't be attached as message/rfc822: its lines are too long or it holds binary data");
+    }
+
+    let encoding = match original.is_ascii() {
+        true => ContentTransferEncoding::SevenBit,
+        false => ContentTransferEncoding::EightBit,
+    };
+    let body = Body::dangerous_pre_encoded(original, encoding);
+
+    Ok(Attachment::new("forwarded.eml".to_string()).body(body, ContentType::parse("message/rfc822")?))
+}
+
+/// The body of a message, before it is (optionally) signed or encrypted
+pub enum Content {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl Content {
+    /// The MIME entity with its headers, as it appears in the message
+    pub fn formatted(&self) -> Vec<u8> {
+        match self {
+            Self::Single(part) => part.formatted(),
+            Self::Multi(multipart) => multipart.formatted(),
+        }
+    }
+
+    /// `Content::formatted`, the form signatures are made over and that is sent as it is.
+    /// Servers turn bare LFs into CRLF on the way, so there must not be any (see `attachment_body`).
+    pub fn canonical(&self) -> anyhow::Result<Vec<u8>> {
+        let formatted = self.formatted();
+        if crlf(&formatted) != formatted {
+            anyhow::bail!("the message has bare line feeds, they would break its signature");
+        }
+
+        Ok(formatted)
+    }
+
+    /// Add the entity as the next part of `builder`
+    pub fn add_to(self, builder: MultiPartBuilder) -> MultiPart {
+        match self {
+            Self::Single(part) => builder.singlepart(part),
+            Self::Multi(multipart) => builder.multipart(multipart),
+        }
+    }
+}
+
+/// Append `signature` to `body`, separated by the standard "-- " line
+fn append_signature(body: &mut String, signature: &str) {
+    if !body.is_empty() && !body.ends_with('\n') {
+        body.push('\n');
+    }
+    body.push_str("-- \n");
+    body.push_str(signature);
+    if !signature.ends_with('\n') {
+        body.push('\n');
+    }
+}
 
-        println!("{}", "-------------------".blue());
-        info(format!("from    : {}", self.username().bold()));
-        info(format!("to      : {}", args.to.as_str().bold()));
-        info(format!("subject : {}", args.subject.as_str().bold()));
-        println!("{}", "-------------------".blue());
+/// The body of an attachment.
+/// Text that is sent as it is (7bit or 8bit) gets CRLF line endings, so that it arrives as it was signed.
+fn attachment_body(data: Vec<u8>) -> Body {
+    let body = Body::new(data);
+    match body.encoding() {
+        ContentTransferEncoding::SevenBit | ContentTransferEncoding::EightBit => Body::new(crlf(&body.into_vec())),
+        _ => body,
+    }
+}
 
-        info("creating transport...");
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl config::ConfigManager {
+    /// The 'server' that sends the mail via SMTP.
+    /// It is only built once, so all messages go through the same (pooled) connection.
+    pub fn transport(&self) -> anyhow::Result<&SmtpTransport> {
+        if let Some(mailer) = self.transport.get() {
+            return Ok(mailer);
+        }
+
+        log::debug("creating transport...");
+
+        // Username & Decrypted Password
+        let credentials = self.credentials();
 
-        // The 'server' that sends the mail via SMTP
         let mailer = SmtpTransport::relay(&self.config.relay_settings.addr)?
             .authentication(self.config.relay_settings.authentication.clone())
             .port(self.config.relay_settings.port)
@@ -47,16 +186,253 @@ impl SendMail for config::ConfigManager {
             .credentials(credentials)
             .build();
 
+        Ok(self.transport.get_or_init(|| mailer))
+    }
+
+    /// The `connecting` event of `--output json` (or a debug message), before the first message goes to the relay
+    pub fn log_connecting(&self) {
+        let relay = &self.config.relay_settings;
+        if !log::is_json() {
+            log::debug(format!("connecting to {}:{} (tls: {})", relay.addr, relay.port, relay.tls));
+        }
+        log::event(
+            "connecting",
+            json!({ "relay": relay.addr, "port": relay.port, "tls": relay.tls }),
+        );
+    }
+
+    /// Like `transport`, but for async code on Tokio.
+    /// Its pool keeps up to `[batch] concurrency` connections, so that many messages can be sent at the same time.
+    #[cfg(feature = "async")]
+    pub fn async_transport(&self) -> anyhow::Result<AsyncSmtpTransport<Tokio1Executor>> {
+        log::debug("creating async transport...");
+
+        let pool = PoolConfig::new().max_size(self.config.batch.concurrency.max(1) as u32);
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&self.config.relay_settings.addr)?
+            .authentication(self.config.relay_settings.authentication.clone())
+            .port(self.config.relay_settings.port)
+            .tls(
+                if self.config.relay_settings.tls {
+                    Tls::Required( TlsParameters::new(self.config.relay_settings.addr.to_string())? )
+                } else {
+                    Tls::None
+                }
+            )
+            .credentials(self.credentials())
+            .pool_config(pool)
+            .build();
+
+        Ok(mailer)
+    }
+
+    /// Write the history, and the archive and Sent folder copies if the message was accepted.
+    /// A broken history shouldn't hide whether the mail was sent, so failures here are only warnings.
+    pub fn delivered(&self, envelope: &Envelope, raw: &[u8], result: Result<&Response, &smtp::Error>) {
+        let message_id = mailparse::parse_headers(raw)
+            .ok()
+            .and_then(|(headers, _)| headers.get_first_value("Message-ID"));
+        let recipients: Vec<String> = envelope.to().iter().map(|to| to.to_string()).collect();
+        match result {
+            Ok(response) => log::event(
+                "sent",
+                json!({
+                    "message_id": message_id,
+                    "recipients": recipients,
+                    "response": {
+                        "code": response.code().to_string(),
+                        "message": response.message().collect::<Vec<_>>().join(" "),
+                    },
+                }),
+            ),
+            Err(err) => log::event(
+                "failed",
+                json!({
+                    "message_id": message_id,
+                    "recipients": recipients,
+                    "category": ErrorClass::of_smtp(err).name(),
+                    "error": err.to_string(),
+                }),
+            ),
+        }
+
+        if let Err(err) = history::record(self, envelope, raw, result) {
+            warning(format!("failed to write the history: {err}"));
+        }
+        if result.is_ok() {
+            if let Err(err) = archive::store(self, envelope, raw) {
+                warning(format!("{err}"));
+            }
+            if let Err(err) = self.append_sent(raw) {
+                warning(format!("failed to copy the message to the Sent folder: {err}"));
+            }
+        }
+    }
+}
+
+impl config::ConfigManager {
+    /// The plain text body with the signature appended, and an HTML variant if there is an HTML body or signature
+    fn body(&self, args: &Args) -> anyhow::Result<(String, Option<String>)> {
+        let signature = match &self.config.signature {
+            Some(signature) if !args.no_signature => signature,
+            _ => return Ok((args.msg.clone(), args.html.clone())),
+        };
+
+        let mut text = args.msg.clone();
+        if let Some(text_signature) = signature.text()? {
+            append_signature(&mut text, &text_signature);
+        }
+
+        let Some(html_signature) = signature.html()? else {
+            return Ok((text, args.html.clone()));
+        };
+
+        let html = match &args.html {
+            Some(html) => format!("{html}\n<div>-- <br>\n{html_signature}</div>\n"),
+            // The HTML variant keeps the line breaks of the plain body
+            None => format!(
+                "<div style=\"white-space: pre-wrap\">{}</div>\n<div>-- <br>\n{html_signature}</div>\n",
+                escape_html(&args.msg)
+            ),
+        };
+
+        Ok((text, Some(html)))
+    }
+}
+
+impl SendMail for config::ConfigManager {
+    fn message(&self, args: &Args) -> anyhow::Result<Message> {
+        let from = self.sender(args.from.as_deref())?;
+
+        if log::is_json() {
+            log::event("message", json!({ "from": from.to_string(), "to": args.to, "subject": args.subject }));
+        } else if log::enabled(log::Level::Info) {
+            log::print(&"-------------------".blue().to_string());
+            info(format!("from    : {}", from.to_string().bold()));
+            info(format!("to      : {}", args.to.as_str().bold()));
+            info(format!("subject : {}", args.subject.as_str().bold()));
+            log::print(&"-------------------".blue().to_string());
+        }
 
         //NOTE: maybe find a way around the cloning.
-        info("building message...");
-        let message = MessageBuilder::new()
-            .from(Mailbox::new(None, self.username().parse().unwrap()))
-            .to(args.to.parse().map_err(|err| anyhow::anyhow!("failed to parse --to '{}': {err}", args.to))?)
+        log::debug("building message...");
+
+        let message_id_domain = args
+            .message_id_domain
+            .as_ref()
+            .or(self.config.message.message_id_domain.as_ref());
+        let message_id = message_id_domain.map(|domain| format!("<{}@{domain}>", crypto::random_hex(16)));
+
+        let mut builder = MessageBuilder::new()
+            .from(from.clone())
             .subject(args.subject.clone())
-            .body(args.msg.clone())?;
+            .message_id(message_id);
 
-        info("sending message...");
-        Ok(mailer.send(&message).map(|_| ())?) // Disregard Ok(value)
+        // --to may hold several comma separated addresses, aliases and @groups
+        let book = AddressBook::load()?;
+        let recipients = book
+            .resolve(&args.to)
+            .map_err(|err| anyhow::anyhow!("failed to parse --to '{}': {err}", args.to))
+            .class(ErrorClass::Input)?;
+        let cc = match args.cc.is_empty() {
+            true => Vec::new(),
+            false => book
+                .resolve(&args.cc)
+                .map_err(|err| anyhow::anyhow!("failed to parse cc '{}': {err}", args.cc))
+                .class(ErrorClass::Input)?,
+        };
+
+        let addresses: Vec<Address> = recipients.iter().chain(&cc).map(|mailbox| mailbox.email.clone()).collect();
+
+        // The envelope sender is where bounces go, receiving servers turn it into the Return-Path
+        if let Some(envelope_from) = args.envelope_from.as_ref().or(self.config.message.envelope_from.as_ref()) {
+            let envelope_from: Address = envelope_from
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid envelope sender '{envelope_from}': {e}"))
+                .class(ErrorClass::Input)?;
+            builder = builder.envelope(Envelope::new(Some(envelope_from), addresses.clone())?);
+        }
+
+        for to in recipients {
+            builder = builder.to(to);
+        }
+        for cc in cc {
+            builder = builder.cc(cc);
+        }
+
+        // Threading headers of replies
+        if let Some(id) = &args.in_reply_to {
+            builder = builder.in_reply_to(id.clone());
+        }
+        if !args.references.is_empty() {
+            builder = builder.references(args.references.join(" "));
+        }
+
+        let (text, html) = self.body(args)?;
+
+        let content = if args.attach_data.is_empty() && args.forwarded.is_none() {
+            match html {
+                Some(html) => Content::Multi(MultiPart::alternative_plain_html(text, html)),
+                None => Content::Single(SinglePart::plain(text)),
+            }
+        } else {
+            let mut mixed = match html {
+                Some(html) => MultiPart::mixed().multipart(MultiPart::alternative_plain_html(text, html)),
+                None => MultiPart::mixed().singlepart(SinglePart::plain(text)),
+            };
+            for (filename, data) in &args.attach_data {
+                let content_type = ContentType::parse(mime_guess::from_path(filename).first_or_octet_stream().as_ref())?;
+                mixed = mixed.singlepart(Attachment::new(filename.clone()).body(attachment_body(data.clone()), content_type));
+            }
+            if let Some(original) = &args.forwarded {
+                mixed = mixed.singlepart(forwarded_attachment(original)?);
+            }
+            Content::Multi(mixed)
+        };
+
+        let content = match (args.sign || args.encrypt, args.smime) {
+            (false, true) => anyhow::bail!("--smime needs --sign and/or --encrypt"),
+            (false, false) => content,
+            (true, false) => Content::Multi(self.pgp_protect(content, args.sign, args.encrypt, &addresses)?),
+            (true, true) => self.smime_protect(content, args.sign, args.encrypt, &addresses)?,
+        };
+
+        let mut message = match content {
+            Content::Single(part) => builder.singlepart(part)?,
+            Content::Multi(multipart) => builder.multipart(multipart)?,
+        };
+
+        if let Some(priority) = args.priority {
+            headers::set_priority(&mut message, priority)?;
+        }
+        if !args.list_unsubscribe.is_empty() {
+            headers::set(&mut message, "List-Unsubscribe", &headers::list_unsubscribe(&args.list_unsubscribe))?;
+        }
+        if args.read_receipt {
+            headers::set(&mut message, "Disposition-Notification-To", &from.to_string())?;
+        }
+
+        // Defaults of the profile first, so --header can override them
+        for (name, value) in &self.config.message.headers {
+            headers::set(&mut message, name, value)
+                .map_err(|e| anyhow::anyhow!("invalid default header in the config: {e}"))?;
+        }
+        for (name, value) in &args.header {
+            headers::set(&mut message, name, value)?;
+        }
+
+        Ok(message)
     }
-}
\ No newline at end of file
+
+    fn sign(&self, message: &mut Message) -> anyhow::Result<()> {
+        self.dkim_sign(message)
+    }
+
+    fn deliver_raw(&self, envelope: &Envelope, raw: &[u8]) -> anyhow::Result<Response> {
+        let transport = self.transport()?;
+        self.log_connecting();
+        let result = transport.send_raw(envelope, raw);
+        self.delivered(envelope, raw, result.as_ref());
+
+        Ok(result?)
+    }
+}
@@ -0,0 +1,209 @@
+//! Mail merge: send a personalized copy of a message to every row of a CSV or JSON file.  
+//! Subject and body are rendered with minijinja (`{{name}}`, `{% if %}`, `{% for %}`).  
+//! Every row is rendered and validated before the first message is sent.  
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use inquire::validator::{ErrorMessage, Validation};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    batch::{BlockingBatch, Progress},
+    config::ConfigManager,
+    info,
+    mail::SendMail,
+    warning, Args,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    #[arg(short, long, help("CSV (with a header row) or JSON (array of objects) file, one row per message"))]
+    pub data: PathBuf,
+    #[arg(short, long, help("file containing the body template"))]
+    pub template: PathBuf,
+    #[arg(short, long, help("subject template, e.g. \"Hello {{name}}\""))]
+    pub subject: String,
+    #[arg(long, default_value = "email", help("column holding the recipient address"))]
+    pub to_column: String,
+    #[arg(short, long, default_value = "merge-report.csv", help("where to write the per-row results"))]
+    pub report: PathBuf,
+}
+
+/// One line of the merge report
+#[derive(Debug, Serialize)]
+struct ReportRow {
+    row: usize,
+    to: String,
+    status: &'static str,
+    detail: String,
+}
+
+/// Read the rows from `path`, based on its extension
+fn read_rows(path: &Path) -> anyhow::Result<Vec<Map<String, Value>>> {
+    let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+    if is_json {
+        let rows: Vec<Map<String, Value>> = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow::anyhow!("failed to read '{}': expected an array of objects: {e}", path.display()))?;
+        return Ok(rows);
+    }
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let row = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(key, value)| (key.to_string(), Value::String(value.to_string())))
+            .collect();
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Render, validate and send one message per row in `args.data`
+pub fn merge(cf: &ConfigManager, args: &MergeArgs) -> anyhow::Result<()> {
+    let rows = read_rows(&args.data)?;
+    let body_template = fs::read_to_string(&args.template)
+        .map_err(|e| anyhow::anyhow!("failed to read template '{}': {e}", args.template.display()))?;
+
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    env.add_template("subject", &args.subject)?;
+    env.add_template("body", &body_template)?;
+
+    // Render everything first, so nothing is sent if a single row is broken
+    let mut messages = Vec::with_capacity(rows.len());
+    let mut invalid = Vec::new();
+
+    for (i, row) in rows.iter().enumerate() {
+        let row_nr = i + 1;
+
+        let to = match row.get(&args.to_column) {
+            Some(Value::String(to)) => to.trim().to_string(),
+            _ => {
+                invalid.push(format!("row {row_nr}: missing column '{}'", args.to_column));
+                continue;
+            }
+        };
+
+        if let Ok(Validation::Invalid(e)) = ConfigManager::email_validator(&to) {
+            let reason = match e {
+                ErrorMessage::Custom(reason) => reason,
+                ErrorMessage::Default => "invalid email".to_string(),
+            };
+            invalid.push(format!("row {row_nr}: invalid address '{to}': {reason}"));
+            continue;
+        }
+
+        let ctx = minijinja::Value::from_serialize(row);
+        let rendered = env
+            .get_template("subject")
+            .and_then(|tmpl| tmpl.render(&ctx))
+            .and_then(|subject| Ok((subject, env.get_template("body")?.render(&ctx)?)));
+
+        match rendered {
+            Ok((subject, msg)) => messages.push((
+                row_nr,
+                Args {
+                    to,
+                    subject,
+                    msg,
+                    ..Default::default()
+                },
+            )),
+            Err(e) => invalid.push(format!("row {row_nr}: failed to render: {e}")),
+        }
+    }
+
+    if !invalid.is_empty() {
+        for problem in &invalid {
+            warning(problem);
+        }
+        anyhow::bail!("{} of {} rows are invalid, nothing was sent", invalid.len(), rows.len());
+    }
+
+    // Built and signed up front, so their output doesn't get between the progress lines
+    let mut built = Vec::with_capacity(messages.len());
+    for (row, msg_args) in messages {
+        let message = cf.message(&msg_args).and_then(|mut message| {
+            cf.sign(&mut message)?;
+            Ok(message)
+        });
+        if let Err(e) = &message {
+            warning(format!("row {row}: failed to build the message to '{}': {e}", msg_args.to));
+        }
+        built.push((row, msg_args.to, message));
+    }
+
+    let mut results = Vec::with_capacity(built.len());
+    let mut jobs = Vec::with_capacity(built.len());
+    let mut recipients = Vec::with_capacity(built.len());
+    let mut unbuilt = 0;
+
+    for (row, to, message) in built {
+        match message {
+            Ok(message) => {
+                jobs.push((message.envelope().clone(), message.formatted()));
+                recipients.push((row, to));
+            }
+            Err(e) => {
+                unbuilt += 1;
+                results.push(ReportRow {
+                    row,
+                    to,
+                    status: "failed",
+                    detail: e.to_string(),
+                });
+            }
+        }
+    }
+
+    info(format!("sending {} messages...", jobs.len()));
+
+    let batch = BlockingBatch::new(cf)?;
+    let mut progress = Progress::new(jobs.len());
+
+    batch.send_all(&jobs, |i, delivery| {
+        let (row, to) = &recipients[i];
+        let (status, detail) = match delivery.result() {
+            Ok(response) => ("sent", response.message().collect::<Vec<_>>().join(" ")),
+            Err(e) => ("failed", e.to_string()),
+        };
+
+        progress.step(&format!("{status}: {to}"));
+        results.push(ReportRow {
+            row: *row,
+            to: to.clone(),
+            status,
+            detail,
+        });
+        Ok(())
+    })?;
+    progress.clear();
+
+    // Messages can finish out of order, the report follows the rows
+    results.sort_by_key(|result| result.row);
+    let mut report = csv::Writer::from_path(&args.report)?;
+    for result in results {
+        report.serialize(result)?;
+    }
+    report.flush()?;
+
+    let mut summary = batch.into_summary();
+    summary.failed += unbuilt;
+    summary.print();
+    info(format!("report written to '{}'", args.report.display().to_string().bold()));
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Helpers for inspecting raw (MIME) messages, e.g. for the history or received mail.  
+
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+
+/// First value of the header `name`, with encoded words decoded
+pub fn header(mail: &ParsedMail, name: &str) -> Option<String> {
+    mail.headers.get_first_value(name)
+}
+
+fn is_attachment(part: &ParsedMail) -> bool {
+    part.get_content_disposition().disposition == DispositionType::Attachment
+}
+
+/// File names of all attachments
+pub fn attachment_names(mail: &ParsedMail) -> Vec<String> {
+    mail.parts()
+        .filter(|part| is_attachment(part))
+        .map(|part| {
+            part.get_content_disposition()
+                .params
+                .get("filename")
+                .cloned()
+                .or(part.ctype.params.get("name").cloned())
+                .unwrap_or("unnamed".to_string())
+        })
+        .collect()
+}
+
+/// The decoded body of the first `mimetype` part that isn't an attachment
+pub fn body_of_type(mail: &ParsedMail, mimetype: &str) -> Option<String> {
+    mail.parts()
+        .filter(|part| !is_attachment(part))
+        .find(|part| part.ctype.mimetype.eq_ignore_ascii_case(mimetype))
+        .and_then(|part| part.get_body().ok())
+}
+
+/// The body as plain text: the text part if there is one, otherwise the HTML part converted to text
+pub fn text_body(mail: &ParsedMail) -> Option<String> {
+    if let Some(text) = body_of_type(mail, "text/plain") {
+        return Some(text);
+    }
+
+    let html = body_of_type(mail, "text/html")?;
+    html2text::from_read(html.as_bytes(), 80).ok()
+}
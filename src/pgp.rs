@@ -0,0 +1,333 @@
+//! OpenPGP signing and encryption of messages as PGP/MIME (RFC 3156).  
+//! The signing key and the public keys of recipients are configured in the `[pgp]` section,  
+//! recipients without a configured key are looked up by address in the keyring directory.  
+
+use std::{fs, path::Path};
+
+use aes_gcm::aead::OsRng;
+use lettre::message::{header::ContentType, MultiPart, SinglePart};
+use lettre::Address;
+use pgp::{
+    composed::{ArmorOptions, Deserializable, DetachedSignature, MessageBuilder, SignedPublicKey, SignedSecretKey},
+    crypto::{hash::HashAlgorithm, sym::SymmetricKeyAlgorithm},
+    types::{KeyDetails, Password},
+};
+
+use crate::{config::ConfigManager, mail::Content};
+
+/// Environment variable holding the passphrase of the secret key, it is asked for otherwise
+const PASSPHRASE_VAR: &str = "MAILR_PGP_PASSPHRASE";
+
+fn read_public_key(path: &Path) -> anyhow::Result<SignedPublicKey> {
+    let data = fs::read(path).map_err(|e| anyhow::anyhow!("failed to read key '{}': {e}", path.display()))?;
+    let (key, _) = SignedPublicKey::from_reader_single(data.as_slice())
+        .map_err(|e| anyhow::anyhow!("failed to parse key '{}': {e}", path.display()))?;
+    Ok(key)
+}
+
+/// Whether one of the user IDs of `key` has the address `address`
+fn has_address(key: &SignedPublicKey, address: &Address) -> bool {
+    key.details.users.iter().any(|user| {
+        let id = String::from_utf8_lossy(user.id.id());
+        // User IDs look like "Name <address>", or are a bare address
+        let id_address = match id.rsplit_once('<') {
+            Some((_, rest)) => rest.trim_end_matches('>'),
+            None => id.trim(),
+        };
+        id_address.eq_ignore_ascii_case(address.as_ref())
+    })
+}
+
+impl ConfigManager {
+    /// The configured secret key, still locked: its public half can be used without the passphrase
+    fn pgp_locked_secret_key(&self) -> anyhow::Result<SignedSecretKey> {
+        let path = self
+            .config
+            .pgp
+            .secret_key
+            .as_ref()
+            .ok_or(anyhow::anyhow!("no secret key configured, set 'secret_key' in the [pgp] section"))?;
+
+        let data = fs::read(path).map_err(|e| anyhow::anyhow!("failed to read key '{}': {e}", path.display()))?;
+        let (key, _) = SignedSecretKey::from_reader_single(data.as_slice())
+            .map_err(|e| anyhow::anyhow!("failed to parse secret key '{}': {e}", path.display()))?;
+        Ok(key)
+    }
+
+    /// The configured secret key with its passphrase, which is asked for if it isn't in the environment
+    fn pgp_secret_key(&self) -> anyhow::Result<(SignedSecretKey, Password)> {
+        let key = self.pgp_locked_secret_key()?;
+        let password = if !key.primary_key.secret_params().is_encrypted() {
+            Password::empty()
+        } else if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+            passphrase.into()
+        } else {
+            inquire::Password::new("passphrase of the PGP key:")
+                .without_confirmation()
+                .prompt()?
+                .into()
+        };
+
+        Ok((key, password))
+    }
+
+    /// The public key of `address`: the configured key file, or the first key in the keyring with that address
+    fn pgp_public_key(&self, address: &Address) -> anyhow::Result<SignedPublicKey> {
+        let settings = &self.config.pgp;
+
+        if let Some((_, path)) = settings
+            .public_keys
+            .iter()
+            .find(|(configured, _)| configured.eq_ignore_ascii_case(address.as_ref()))
+        {
+            return read_public_key(path);
+        }
+
+        let keyring = match &settings.keyring {
+            Some(keyring) => keyring.clone(),
+            None => ConfigManager::data_dir()?.join("keyring"),
+        };
+
+        if keyring.is_dir() {
+            for file in fs::read_dir(&keyring)? {
+                let path = file?.path();
+                let is_key = path
+                    .extension()
+                    .is_some_and(|ext| ext == "asc" || ext == "pgp" || ext == "gpg");
+                if !is_key {
+                    continue;
+                }
+
+                let key = read_public_key(&path)?;
+                if has_address(&key, address) {
+                    return Ok(key);
+                }
+            }
+        }
+
+        anyhow::bail!("no public key for '{address}' (searched the [pgp] section and '{}')", keyring.display())
+    }
+
+    /// Sign and/or encrypt `content` for `recipients`.
+    /// All keys are loaded first, so a missing key fails before anything is sent.
+    pub fn pgp_protect(
+        &self,
+        content: Content,
+        sign: bool,
+        encrypt: bool,
+        recipients: &[Address],
+    ) -> anyhow::Result<MultiPart> {
+        let settings = &self.config.pgp;
+        let secret_key = match sign {
+            true => Some(self.pgp_secret_key()?),
+            false => None,
+        };
+
+        let mut public_keys = Vec::new();
+        if encrypt {
+            for address in recipients {
+                public_keys.push(self.pgp_public_key(address)?);
+            }
+            // Encrypting needs no passphrase, so it's only asked for to sign
+            if settings.encrypt_to_self {
+                match &secret_key {
+                    Some((key, _)) => public_keys.push(key.to_public_key()),
+                    None if settings.secret_key.is_some() => public_keys.push(self.pgp_locked_secret_key()?.to_public_key()),
+                    None => {}
+                }
+            }
+        }
+
+        let signed = match (&secret_key, sign) {
+            (Some((key, password)), true) => Content::Multi(sign_content(content, key, password)?),
+            _ => content,
+        };
+
+        if !encrypt {
+            return match signed {
+                Content::Multi(multipart) => Ok(multipart),
+                Content::Single(_) => unreachable!("signed content is always multipart/signed"),
+            };
+        }
+
+        encrypt_content(signed, &public_keys)
+    }
+}
+
+/// multipart/signed with a detached signature over the canonical form of `content`
+fn sign_content(content: Content, key: &SignedSecretKey, password: &Password) -> anyhow::Result<MultiPart> {
+    // The line break before the boundary belongs to the boundary, not to the signed part
+    let canonical = content.canonical()?;
+    let signed_data = canonical.strip_suffix(b"\r\n").unwrap_or(&canonical);
+
+    let signature = DetachedSignature::sign_binary_data(
+        OsRng,
+        &key.primary_key,
+        password,
+        HashAlgorithm::Sha256,
+        signed_data,
+    )
+    .map_err(|e| anyhow::anyhow!("failed to sign the message: {e}"))?;
+
+    let signature = SinglePart::builder()
+        .content_type(ContentType::parse("application/pgp-signature; name=\"signature.asc\"")?)
+        .body(signature.to_armored_string(ArmorOptions::default())?);
+
+    let multipart = MultiPart::signed("application/pgp-signature".to_string(), "pgp-sha256".to_string());
+    Ok(content.add_to(multipart).singlepart(signature))
+}
+
+/// multipart/encrypted holding `content` encrypted to every key in `keys`
+fn encrypt_content(content: Content, keys: &[SignedPublicKey]) -> anyhow::Result<MultiPart> {
+    let mut builder = MessageBuilder::from_bytes("", content.canonical()?).seipd_v1(OsRng, SymmetricKeyAlgorithm::AES256);
+
+    for key in keys {
+        // Prefer an encryption subkey, fall back to the primary key
+        match key.public_subkeys.iter().find(|subkey| subkey.key.algorithm().can_encrypt()) {
+            Some(subkey) => builder.encrypt_to_key(OsRng, &subkey.key)?,
+            None if key.primary_key.algorithm().can_encrypt() => builder.encrypt_to_key(OsRng, &key.primary_key)?,
+            None => anyhow::bail!("key {} can't be used for encryption", key.legacy_key_id()),
+        };
+    }
+
+    let encrypted = builder
+        .to_armored_string(OsRng, ArmorOptions::default())
+        .map_err(|e| anyhow::anyhow!("failed to encrypt the message: {e}"))?;
+
+    Ok(MultiPart::encrypted("application/pgp-encrypted".to_string())
+        .singlepart(
+            SinglePart::builder()
+                .content_type(ContentType::parse("application/pgp-encrypted")?)
+                .body("Version: 1\r\n".to_string()),
+        )
+        .singlepart(
+            SinglePart::builder()
+                .content_type(ContentType::parse("application/octet-stream; name=\"encrypted.asc\"")?)
+                .body(encrypted),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use pgp::{
+        composed::{EncryptionCaps, KeyType, Message, SecretKeyParamsBuilder, SubkeyParamsBuilder},
+        crypto::ecc_curve::ECCCurve,
+    };
+
+    use super::*;
+    use crate::test_support::config_with;
+
+    /// A throwaway key for `address`: an Ed25519 primary key that signs and a Curve25519 subkey that encrypts
+    fn generate_key(address: &str, passphrase: Option<&str>) -> SignedSecretKey {
+        let mut subkey = SubkeyParamsBuilder::default();
+        subkey
+            .key_type(KeyType::ECDH(ECCCurve::Curve25519Legacy))
+            .can_encrypt(EncryptionCaps::All)
+            .passphrase(passphrase.map(str::to_string));
+
+        let mut params = SecretKeyParamsBuilder::default();
+        params
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id(format!("Test <{address}>"))
+            .passphrase(passphrase.map(str::to_string))
+            .subkeys(vec![subkey.build().unwrap()]);
+        params.build().unwrap().generate(OsRng).unwrap()
+    }
+
+    fn write_key(name: &str, armored: String) -> PathBuf {
+        let path = env::temp_dir().join(format!("mailr-test-{}-{name}.asc", process::id()));
+        fs::write(&path, armored).unwrap();
+        path
+    }
+
+    /// A config with `own` as the secret key and `public` as the keys of their recipients
+    fn config(own: &SignedSecretKey, name: &str, public: &[(&str, &SignedSecretKey)]) -> ConfigManager {
+        let secret_key = write_key(&format!("{name}-own"), own.to_armored_string(ArmorOptions::default()).unwrap());
+        let mut public_keys = String::new();
+        for (address, key) in public {
+            let armored = key.to_public_key().to_armored_string(ArmorOptions::default()).unwrap();
+            let path = write_key(&format!("{name}-{address}"), armored);
+            public_keys.push_str(&format!("\"{address}\" = '{}'\n", path.display()));
+        }
+
+        config_with(
+            25,
+            &format!(
+                "[pgp]\nsecret_key = '{}'\nkeyring = '{}'\nencrypt_to_self = true\n[pgp.public_keys]\n{public_keys}",
+                secret_key.display(),
+                env::temp_dir().join(format!("mailr-test-{}-no-keyring", process::id())).display(),
+            ),
+        )
+    }
+
+    fn content() -> Content {
+        Content::Single(SinglePart::plain("hello\r\n-- \r\nme\r\n".to_string()))
+    }
+
+    fn address(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    /// The bytes between the first two delimiters of `multipart`: what a signature covers (RFC 3156 5)
+    fn first_part(formatted: &[u8], boundary: &str) -> Vec<u8> {
+        let text = String::from_utf8(formatted.to_vec()).unwrap();
+        let delimiter = format!("--{boundary}\r\n");
+        let start = text.find(&delimiter).unwrap() + delimiter.len();
+        let end = start + text[start..].find(&format!("\r\n--{boundary}")).unwrap();
+        text[start..end].as_bytes().to_vec()
+    }
+
+    #[test]
+    fn signature_covers_the_part_as_it_is_sent() {
+        let key = generate_key("me@example.com", None);
+        let cf = config(&key, "sign", &[]);
+
+        let formatted = cf.pgp_protect(content(), true, false, &[]).unwrap().formatted();
+        let mail = mailparse::parse_mail(&formatted).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/signed");
+
+        // The line break after the part belongs to the delimiter
+        let signed = first_part(&formatted, &mail.ctype.params["boundary"]);
+        assert_eq!(signed, content().formatted().strip_suffix(b"\r\n").unwrap());
+
+        let (signature, _) = DetachedSignature::from_string(&mail.subparts[1].get_body().unwrap()).unwrap();
+        let public = key.to_public_key();
+        signature.verify(&public.primary_key, &signed).unwrap();
+        assert!(signature.verify(&public.primary_key, &[&signed[..], b"x"].concat()).is_err());
+    }
+
+    #[test]
+    fn encrypted_content_decrypts_for_the_recipient_and_the_sender() {
+        // Encrypting to the own key needs no passphrase, with one it would be asked for and fail here
+        let own = generate_key("me@example.com", Some("secret"));
+        let recipient = generate_key("to@example.com", None);
+        let cf = config(&own, "encrypt", &[("to@example.com", &recipient)]);
+
+        let formatted = cf.pgp_protect(content(), false, true, &[address("to@example.com")]).unwrap().formatted();
+        let mail = mailparse::parse_mail(&formatted).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/encrypted");
+        let armored = mail.subparts[1].get_body().unwrap();
+
+        for (key, password) in [(&recipient, Password::empty()), (&own, "secret".into())] {
+            let (message, _) = Message::from_string(&armored).unwrap();
+            let mut decrypted = message.decrypt(&password, key).unwrap();
+            assert_eq!(decrypted.as_data_vec().unwrap(), content().formatted());
+        }
+    }
+
+    #[test]
+    fn missing_recipient_keys_fail_up_front() {
+        let own = generate_key("me@example.com", None);
+        let recipient = generate_key("to@example.com", None);
+        let cf = config(&own, "missing", &[("to@example.com", &recipient)]);
+
+        let to = [address("to@example.com"), address("nobody@example.com")];
+        let err = cf.pgp_protect(content(), true, true, &to).unwrap_err();
+
+        assert!(err.to_string().contains("no public key for 'nobody@example.com'"), "{err}");
+    }
+}
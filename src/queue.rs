@@ -0,0 +1,409 @@
+//! Persistent outbox for mail that could not be delivered (yet).  
+//! Every entry is stored as two files in the outbox directory:  
+//! `<id>.eml` holds the raw message, `<id>.toml` the envelope and retry metadata.  
+
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use colored::Colorize;
+use lettre::{address::Envelope, transport::smtp, Message};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::{BlockingBatch, Refused},
+    config::{ConfigManager, QueueSettings},
+    crypto, info, warning,
+};
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Should delivery of a message that failed with `err` be retried later?
+/// Permanent (5xx) SMTP errors and client misconfiguration are final, transient (4xx) and connection errors are not.
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<smtp::Error>().is_some_and(is_retryable_smtp)
+}
+
+/// `is_retryable` for an SMTP error
+pub fn is_retryable_smtp(err: &smtp::Error) -> bool {
+    !err.is_permanent() && !err.is_client()
+}
+
+/// Ids are made up of hex digits and a dash, anything else given by the user can't name an entry
+/// (and must not reach outside the store, e.g. `../x`)
+pub fn check_id(id: &str) -> anyhow::Result<()> {
+    if id.is_empty() || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f' | b'-')) {
+        anyhow::bail!("'{id}' is not a valid id");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueEntry {
+    #[serde(skip)]
+    pub id: String,
+    pub subject: String,
+    /// Creation time (unix seconds)
+    pub created: u64,
+    pub attempts: u32,
+    /// Earliest time (unix seconds) of the next delivery attempt
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+    /// Set once the message is given up on, it will not be retried anymore
+    #[serde(default)]
+    pub failed: bool,
+    pub envelope: Envelope,
+}
+
+impl QueueEntry {
+    /// Record a failed attempt and schedule the next one with exponential backoff
+    fn attempt_failed(&mut self, err: &anyhow::Error, settings: &QueueSettings) {
+        self.record_failure(err.to_string(), is_retryable(err), settings);
+    }
+
+    /// Record a failed attempt for the `refused` recipients, they are all that is left of the entry
+    fn attempt_refused(&mut self, refused: Refused, settings: &QueueSettings) {
+        self.envelope = refused.envelope;
+        self.record_failure(refused.reason, refused.retryable, settings);
+    }
+
+    fn record_failure(&mut self, error: String, retryable: bool, settings: &QueueSettings) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+
+        let delay = settings
+            .base_delay_secs
+            .saturating_mul(1 << self.attempts.saturating_sub(1).min(16));
+        self.next_attempt = now() + delay;
+
+        if !retryable {
+            self.failed = true;
+        }
+    }
+
+    fn expired(&self, settings: &QueueSettings) -> bool {
+        now().saturating_sub(self.created) > settings.max_age_hours * 60 * 60
+    }
+}
+
+/// Outcome of `Outbox::flush`
+#[derive(Debug, Default)]
+pub struct FlushSummary {
+    pub sent: usize,
+    pub deferred: usize,
+    pub failed: usize,
+}
+
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    /// Open (and create if needed) the outbox in the data directory
+    pub fn open() -> anyhow::Result<Self> {
+        let dir = ConfigManager::data_dir()?.join("outbox");
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.toml"))
+    }
+
+    fn raw_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.eml"))
+    }
+
+    /// Store `message` in the outbox and return its id
+    pub fn push(
+        &self,
+        message: &Message,
+        subject: &str,
+        err: Option<&anyhow::Error>,
+        settings: &QueueSettings,
+    ) -> anyhow::Result<String> {
+        self.push_raw(message.envelope(), &message.formatted(), subject, err, settings)
+    }
+
+    /// Store an already formatted message in the outbox and return its id.
+    /// If `err` is a permanent error, the entry is marked as failed right away.
+    pub fn push_raw(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+        subject: &str,
+        err: Option<&anyhow::Error>,
+        settings: &QueueSettings,
+    ) -> anyhow::Result<String> {
+        self.store(envelope, raw, subject, |entry| {
+            if let Some(err) = err {
+                entry.attempt_failed(err, settings);
+            }
+        })
+    }
+
+    /// Store an already formatted message for the `refused` recipients of a delivery and return its id.
+    /// Recipients refused for good are kept as a failed entry, so `mailr queue list` shows them.
+    pub fn push_refused(
+        &self,
+        refused: Refused,
+        raw: &[u8],
+        subject: &str,
+        settings: &QueueSettings,
+    ) -> anyhow::Result<String> {
+        let envelope = refused.envelope.clone();
+        self.store(&envelope, raw, subject, |entry| entry.attempt_refused(refused, settings))
+    }
+
+    /// Write a new entry, after `update` has recorded how it got here
+    fn store(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+        subject: &str,
+        update: impl FnOnce(&mut QueueEntry),
+    ) -> anyhow::Result<String> {
+        let created = now();
+        let mut entry = QueueEntry {
+            id: format!("{created:x}-{}", crypto::random_hex(4)),
+            subject: subject.to_string(),
+            created,
+            attempts: 0,
+            next_attempt: created,
+            last_error: None,
+            failed: false,
+            envelope: envelope.clone(),
+        };
+        update(&mut entry);
+
+        fs::write(self.raw_path(&entry.id), raw)?;
+        self.write_entry(&entry)?;
+
+        Ok(entry.id)
+    }
+
+    fn write_entry(&self, entry: &QueueEntry) -> anyhow::Result<()> {
+        fs::write(self.meta_path(&entry.id), toml::to_string_pretty(entry)?)?;
+        Ok(())
+    }
+
+    /// All entries in the outbox, oldest first
+    pub fn list(&self) -> anyhow::Result<Vec<QueueEntry>> {
+        let mut entries = Vec::new();
+
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                let id = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                let mut entry: QueueEntry = toml::from_str(&fs::read_to_string(&path)?)
+                    .map_err(|e| anyhow::anyhow!("failed to read queue entry '{}': {e}", path.display()))?;
+                entry.id = id;
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by_key(|entry| entry.created);
+        Ok(entries)
+    }
+
+    /// Remove the entry with `id` from the outbox
+    pub fn remove(&self, id: &str) -> anyhow::Result<()> {
+        check_id(id)?;
+        let meta_path = self.meta_path(id);
+        if !meta_path.is_file() {
+            anyhow::bail!("no queued message with id '{id}'");
+        }
+
+        fs::remove_file(meta_path)?;
+        let _ = fs::remove_file(self.raw_path(id));
+        Ok(())
+    }
+
+    /// Try to deliver all queued messages that are due.
+    /// When `force` is set, the backoff delay is ignored.
+    pub fn flush(&self, cf: &ConfigManager, force: bool) -> anyhow::Result<FlushSummary> {
+        let settings = &cf.config.queue;
+        let mut summary = FlushSummary::default();
+        let mut jobs = Vec::new();
+        let mut due = Vec::new();
+
+        for mut entry in self.list()? {
+            if entry.failed {
+                continue;
+            }
+
+            if entry.expired(settings) {
+                warning(format!("giving up on '{}': older than {} hours", entry.id, settings.max_age_hours));
+                entry.failed = true;
+                self.write_entry(&entry)?;
+                summary.failed += 1;
+                continue;
+            }
+
+            if !force && entry.next_attempt > now() {
+                summary.deferred += 1;
+                continue;
+            }
+
+            info(format!("delivering '{}' ({})", entry.id, entry.subject.as_str().bold()));
+            let raw = fs::read(self.raw_path(&entry.id))?;
+            jobs.push((entry.envelope.clone(), raw));
+            due.push(entry);
+        }
+
+        // An empty outbox doesn't need a transport
+        if due.is_empty() {
+            return Ok(summary);
+        }
+
+        BlockingBatch::new(cf)?.send_all(&jobs, |i, delivery| {
+            let entry = &mut due[i];
+            match delivery.result() {
+                Ok(_) => {
+                    self.remove(&entry.id)?;
+                    summary.sent += 1;
+                }
+                Err(err) => {
+                    warning(format!("failed to deliver '{}': {err}", entry.id));
+
+                    // Only the refused recipients are tried again, and only if their failure is temporary.
+                    // Those refused for good get a failed entry of their own, unless they are all that is left.
+                    match (delivery.refused(&entry.envelope, true), delivery.refused(&entry.envelope, false)) {
+                        (Some(retry), failed) => {
+                            if let Some(failed) = failed {
+                                let id = self.push_refused(failed, &jobs[i].1, &entry.subject, settings)?;
+                                warning(format!("gave up on the recipients of '{}' refused for good, see '{id}'", entry.id));
+                                summary.failed += 1;
+                            }
+                            entry.attempt_refused(retry, settings);
+                            summary.deferred += 1;
+                        }
+                        (None, Some(failed)) => {
+                            entry.attempt_refused(failed, settings);
+                            summary.failed += 1;
+                        }
+                        (None, None) => unreachable!("a failed delivery has refused recipients"),
+                    }
+                    self.write_entry(entry)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use lettre::{SmtpTransport, Transport};
+
+    use super::*;
+    use crate::test_support::fake_relay;
+
+    /// The error of sending a message to a server on `port`
+    fn send_error(port: u16) -> anyhow::Error {
+        let transport = SmtpTransport::builder_dangerous("127.0.0.1").port(port).build();
+        let envelope = Envelope::new(None, vec!["to@example.com".parse().unwrap()]).unwrap();
+
+        transport.send_raw(&envelope, b"Subject: test\r\n\r\nhello\r\n").unwrap_err().into()
+    }
+
+    fn entry() -> QueueEntry {
+        QueueEntry {
+            id: "1-ab".to_string(),
+            subject: "test".to_string(),
+            created: now(),
+            attempts: 0,
+            next_attempt: now(),
+            last_error: None,
+            failed: false,
+            envelope: Envelope::new(None, vec!["to@example.com".parse().unwrap()]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn transient_replies_are_retried() {
+        assert!(is_retryable(&send_error(fake_relay(|_| "450 mailbox busy").0)));
+    }
+
+    #[test]
+    fn permanent_replies_are_not_retried() {
+        assert!(!is_retryable(&send_error(fake_relay(|_| "550 no such user").0)));
+    }
+
+    #[test]
+    fn connection_errors_are_retried() {
+        // Nothing listens on the port anymore
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        assert!(is_retryable(&send_error(port)));
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        assert!(!is_retryable(&anyhow::anyhow!("no recipients")));
+    }
+
+    #[test]
+    fn backoff_doubles_after_every_attempt() {
+        let settings = QueueSettings::default();
+        let err = send_error(fake_relay(|_| "451 try again later").0);
+        let mut entry = entry();
+
+        let mut delays = Vec::new();
+        for _ in 0..4 {
+            let start = now();
+            entry.attempt_failed(&err, &settings);
+            delays.push(entry.next_attempt - start);
+        }
+
+        // `now` may have ticked over between `start` and the attempt
+        for (delay, expected) in delays.into_iter().zip([60, 120, 240, 480]) {
+            assert!((expected..=expected + 1).contains(&delay), "{delay} != {expected}");
+        }
+        assert_eq!(entry.attempts, 4);
+        assert!(!entry.failed);
+        assert_eq!(entry.last_error, Some(err.to_string()));
+    }
+
+    #[test]
+    fn permanent_failure_gives_up() {
+        let mut entry = entry();
+        entry.attempt_failed(&send_error(fake_relay(|_| "550 no such user").0), &QueueSettings::default());
+
+        assert!(entry.failed);
+    }
+
+    #[test]
+    fn entries_expire_after_max_age() {
+        let settings = QueueSettings::default();
+        let hour = 60 * 60;
+        let mut entry = entry();
+
+        entry.created = now() - (settings.max_age_hours - 1) * hour;
+        assert!(!entry.expired(&settings));
+
+        entry.created = now() - (settings.max_age_hours + 1) * hour;
+        assert!(entry.expired(&settings));
+    }
+
+    #[test]
+    fn ids_are_checked() {
+        assert!(check_id("6712f3a0-9c1e").is_ok());
+
+        for id in ["", "../x", "..", "a/b", "a\\b", "ABC", "1-ab.toml"] {
+            assert!(check_id(id).is_err(), "{id:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Replying to and forwarding received messages.  
+//! The original is read from a file or fetched by UID over IMAP, the new message is sent  
+//! like any other, so `--queue`, history and the sent archive all apply.  
+
+use std::{fs, path::Path};
+
+use mailparse::{addrparse_header, MailAddr, MailHeaderMap, ParsedMail};
+
+use crate::{config::ConfigManager, inbox, mime, send_or_queue, Args};
+
+#[derive(clap::Args, Debug)]
+pub struct ReplyArgs {
+    /// UID of a received message, or a .eml file
+    pub source: String,
+    #[arg(short, long, action, help("reply to all recipients, not only the sender"))]
+    pub all: bool,
+    #[arg(short, long, help("the reply, asked for if not given"))]
+    pub msg: Option<String>,
+    #[arg(short, long, default_value = "INBOX", help("folder of the message, if SOURCE is a UID"))]
+    pub folder: String,
+    #[arg(long, action, help("don't append the configured signature"))]
+    pub no_signature: bool,
+    #[arg(long, action, help("put the reply in the outbox instead of sending it now"))]
+    pub queue: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ForwardArgs {
+    /// UID of a received message, or a .eml file
+    pub source: String,
+    #[arg(short, long)]
+    pub to: String,
+    #[arg(short, long, default_value = "", help("text to put above the forwarded message"))]
+    pub msg: String,
+    #[arg(long, action, help("attach the original as a message/rfc822 part instead of quoting it"))]
+    pub as_attachment: bool,
+    #[arg(short, long, default_value = "INBOX", help("folder of the message, if SOURCE is a UID"))]
+    pub folder: String,
+    #[arg(long, action, help("don't append the configured signature"))]
+    pub no_signature: bool,
+    #[arg(long, action, help("put the message in the outbox instead of sending it now"))]
+    pub queue: bool,
+}
+
+/// Read the original message: a file if `source` is a path, otherwise a UID on the IMAP server
+fn load(cf: &ConfigManager, source: &str, folder: &str) -> anyhow::Result<Vec<u8>> {
+    if Path::new(source).is_file() {
+        return Ok(fs::read(source)?);
+    }
+
+    let uid: u32 = source
+        .parse()
+        .map_err(|_| anyhow::anyhow!("'{source}' is neither a file nor a message UID"))?;
+    inbox::fetch(cf, folder, uid)
+}
+
+/// Prefix `subject` with `prefix` ("Re:" / "Fwd:"), unless it already is
+fn prefixed(prefix: &str, subject: &str) -> String {
+    let subject = subject.trim();
+    match subject.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => subject.to_string(),
+        _ => format!("{prefix} {subject}"),
+    }
+}
+
+/// All addresses in the header `name`, without display names
+fn addresses(mail: &ParsedMail, name: &str) -> anyhow::Result<Vec<String>> {
+    let mut addresses = Vec::new();
+
+    for header in mail.headers.get_all_headers(name) {
+        for addr in addrparse_header(header)?.iter() {
+            match addr {
+                MailAddr::Single(single) => addresses.push(single.addr.clone()),
+                MailAddr::Group(group) => addresses.extend(group.addrs.iter().map(|single| single.addr.clone())),
+            }
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// The original text with every line prefixed by "> "
+fn quote(mail: &ParsedMail) -> String {
+    let text = mime::text_body(mail).unwrap_or_default();
+    let date = mime::header(mail, "Date").unwrap_or("an unknown date".to_string());
+    let from = mime::header(mail, "From").unwrap_or("someone".to_string());
+
+    let mut quoted = format!("On {date}, {from} wrote:\n");
+    for line in text.trim_end().lines() {
+        if line.starts_with('>') {
+            quoted.push_str(&format!(">{line}\n"));
+        } else {
+            quoted.push_str(&format!("> {line}\n"));
+        }
+    }
+    quoted
+}
+
+/// The Message-ID of `mail` and the References of a message answering it:
+/// the thread of the original, followed by the original itself
+fn thread(mail: &ParsedMail) -> (Option<String>, Vec<String>) {
+    let message_id = mime::header(mail, "Message-ID");
+    let mut references: Vec<String> = mime::header(mail, "References")
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    if references.is_empty() {
+        references.extend(mime::header(mail, "In-Reply-To"));
+    }
+    references.extend(message_id.clone());
+
+    (message_id, references)
+}
+
+/// `mailr reply`
+pub fn reply(cf: &ConfigManager, args: &ReplyArgs) -> anyhow::Result<()> {
+    let raw = load(cf, &args.source, &args.folder)?;
+    let mail = mailparse::parse_mail(&raw)?;
+
+    let identities = cf.identities();
+    let is_own = |addr: &String| identities.iter().any(|identity| identity.address.eq_ignore_ascii_case(addr));
+
+    let mut recipients = addresses(&mail, "To")?;
+    recipients.extend(addresses(&mail, "Cc")?);
+    // Reply from the identity the original was sent to, e.g. support@
+    let from = recipients.iter().find(|addr| is_own(addr)).cloned();
+
+    let mut to = addresses(&mail, "Reply-To")?;
+    if to.is_empty() {
+        to = addresses(&mail, "From")?;
+    }
+    if args.all {
+        to.extend(recipients);
+        to.retain(|addr| !is_own(addr));
+    }
+    if to.is_empty() {
+        anyhow::bail!("the original message has no sender to reply to");
+    }
+
+    let msg = match &args.msg {
+        Some(msg) => msg.clone(),
+        None => inquire::prompt_text("reply:")?,
+    };
+
+    let (message_id, references) = thread(&mail);
+
+    let args = Args {
+        from,
+        to: to.join(", "),
+        subject: prefixed("Re:", &mime::header(&mail, "Subject").unwrap_or_default()),
+        msg: format!("{msg}\n\n{}", quote(&mail)),
+        no_signature: args.no_signature,
+        queue: args.queue,
+        in_reply_to: message_id,
+        references,
+        ..Default::default()
+    };
+
+    send_or_queue(cf, &args)
+}
+
+/// `mailr forward`
+pub fn forward(cf: &ConfigManager, args: &ForwardArgs) -> anyhow::Result<()> {
+    let raw = load(cf, &args.source, &args.folder)?;
+    let mail = mailparse::parse_mail(&raw)?;
+
+    let mut msg = args.msg.clone();
+    let forwarded = if args.as_attachment {
+        Some(raw.clone())
+    } else {
+        if !msg.is_empty() {
+            msg.push_str("\n\n");
+        }
+        msg.push_str("---------- Forwarded message ----------\n");
+        for name in ["From", "Date", "Subject", "To", "Cc"] {
+            if let Some(value) = mime::header(&mail, name) {
+                msg.push_str(&format!("{name}: {value}\n"));
+            }
+        }
+        msg.push('\n');
+        msg.push_str(&mime::text_body(&mail).unwrap_or_default());
+        None
+    };
+
+    // Keep the forward in the thread of the original, if it can be identified
+    let (message_id, references) = match thread(&mail) {
+        (Some(message_id), references) => (Some(message_id), references),
+        (None, _) => (None, Vec::new()),
+    };
+
+    let args = Args {
+        to: args.to.clone(),
+        subject: prefixed("Fwd:", &mime::header(&mail, "Subject").unwrap_or_default()),
+        msg,
+        no_signature: args.no_signature,
+        queue: args.queue,
+        in_reply_to: message_id,
+        references,
+        forwarded,
+        ..Default::default()
+    };
+
+    send_or_queue(cf, &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_ends_with_the_original() {
+        let raw = b"Message-ID: <3@example.com>\r\nReferences: <1@example.com> <2@example.com>\r\nIn-Reply-To: <2@example.com>\r\n\r\nbody\r\n";
+        let (message_id, references) = thread(&mailparse::parse_mail(raw).unwrap());
+
+        assert_eq!(message_id.as_deref(), Some("<3@example.com>"));
+        assert_eq!(references, ["<1@example.com>", "<2@example.com>", "<3@example.com>"]);
+    }
+
+    #[test]
+    fn thread_falls_back_to_in_reply_to() {
+        let raw = b"Message-ID: <2@example.com>\r\nIn-Reply-To: <1@example.com>\r\n\r\nbody\r\n";
+        let (_, references) = thread(&mailparse::parse_mail(raw).unwrap());
+
+        assert_eq!(references, ["<1@example.com>", "<2@example.com>"]);
+    }
+
+    #[test]
+    fn subjects_are_prefixed_once() {
+        assert_eq!(prefixed("Fwd:", "news"), "Fwd: news");
+        assert_eq!(prefixed("Fwd:", "FWD: news"), "FWD: news");
+        assert_eq!(prefixed("Re:", " re: news "), "re: news");
+    }
+}
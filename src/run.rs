@@ -0,0 +1,381 @@
+//! `mailr run`: run a command and mail its exit status and output, for cron jobs.  
+//! stdout and stderr are captured together, only the last `--max-output` bytes are kept.  
+//! Output that is too long for the body is attached as `output.txt` instead.  
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Read,
+    process::{self, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+
+use crate::{config::ConfigManager, info, send_or_queue, templates, warning, Args};
+
+/// Output longer than this goes into an attachment
+const INLINE_OUTPUT: usize = 32 * 1024;
+
+/// How long the rest of the output may take after the command exited.
+/// Processes it left running in the background can hold its stdout and stderr open for much longer.
+const OUTPUT_GRACE: Duration = Duration::from_secs(2);
+
+const DEFAULT_SUBJECT: &str = "[{{ status }}] {{ command }} on {{ host }}";
+
+/// Variables available in `--subject`
+const SUBJECT_VARS: [&str; 6] = ["status", "command", "args", "host", "code", "duration"];
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum When {
+    /// Only when the command fails
+    Failure,
+    /// After every run
+    Always,
+    /// When the command fails or prints anything
+    Output,
+}
+
+impl When {
+    /// Whether a run that ended with `outcome` is reported
+    fn reports(self, outcome: &Outcome) -> bool {
+        match self {
+            Self::Failure => !outcome.success(),
+            Self::Always => true,
+            Self::Output => !outcome.success() || !outcome.output.is_empty(),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RunArgs {
+    #[arg(short, long, help("where to send the report"))]
+    pub to: String,
+    #[arg(long, value_enum, default_value_t = When::Failure, help("when to send the report"))]
+    pub on: When,
+    #[arg(
+        short,
+        long,
+        default_value = DEFAULT_SUBJECT,
+        help("subject template, with {{status}}, {{command}}, {{args}}, {{host}}, {{code}} and {{duration}}")
+    )]
+    pub subject: String,
+    #[arg(short, long, value_name("IDENTITY"), help("send as one of the configured identities instead of the default"))]
+    pub from: Option<String>,
+    #[arg(long, value_name("BYTES"), default_value_t = 1024 * 1024, help("keep at most the last BYTES of output"))]
+    pub max_output: usize,
+    #[arg(long, action, help("put the report in the outbox instead of sending it now"))]
+    pub queue: bool,
+    /// The command and its arguments, after `--`
+    #[arg(last = true, required = true, value_name("COMMAND"))]
+    pub command: Vec<String>,
+}
+
+/// The last `cap` bytes written to it
+struct Tail {
+    buf: VecDeque<u8>,
+    cap: usize,
+    dropped: usize,
+}
+
+impl Tail {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        if self.buf.len() > self.cap {
+            let excess = self.buf.len() - self.cap;
+            self.buf.drain(..excess);
+            self.dropped += excess;
+        }
+    }
+}
+
+/// How a run ended
+struct Outcome {
+    /// Human readable status, e.g. "exit code 1"
+    status: String,
+    /// The exit code, 127 if the command couldn't be started, like a shell
+    code: i32,
+    output: Vec<u8>,
+    dropped: usize,
+    duration: Duration,
+}
+
+impl Outcome {
+    fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Copy everything from `reader` into `tail` on a thread of its own, until the end or an error.
+/// `done` is told when it has finished.
+fn capture(mut reader: impl Read + Send + 'static, tail: &Arc<Mutex<Tail>>, done: &mpsc::Sender<()>) {
+    let (tail, done) = (tail.clone(), done.clone());
+    thread::spawn(move || {
+        let mut chunk = [0u8; 8192];
+        while let Ok(n @ 1..) = reader.read(&mut chunk) {
+            tail.lock().unwrap().push(&chunk[..n]);
+        }
+        let _ = done.send(());
+    });
+}
+
+fn describe(status: ExitStatus) -> (String, i32) {
+    if let Some(code) = status.code() {
+        return (format!("exit code {code}"), code);
+    }
+
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return (format!("killed by signal {signal}"), 128 + signal);
+    }
+
+    ("terminated".to_string(), 1)
+}
+
+/// Name of this machine, `localhost` if it can't be found
+pub fn hostname() -> String {
+    hostname::get()
+        .map(|host| host.to_string_lossy().to_string())
+        .unwrap_or("localhost".to_string())
+}
+
+/// The command line as it could be typed in a shell
+fn command_line(command: &[String]) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=.,/:@%".contains(c);
+    command
+        .iter()
+        .map(|arg| match arg.is_empty() || !arg.chars().all(plain) {
+            true => format!("'{}'", arg.replace('\'', "'\\''")),
+            false => arg.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn execute(command: &[String], max_output: usize) -> Outcome {
+    let tail = Arc::new(Mutex::new(Tail {
+        buf: VecDeque::new(),
+        cap: max_output,
+        dropped: 0,
+    }));
+    let start = Instant::now();
+
+    let child = process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    let (status, code) = match child {
+        Ok(mut child) => {
+            let (done, finished) = mpsc::channel();
+            let mut readers = 0;
+            if let Some(stdout) = child.stdout.take() {
+                capture(stdout, &tail, &done);
+                readers += 1;
+            }
+            if let Some(stderr) = child.stderr.take() {
+                capture(stderr, &tail, &done);
+                readers += 1;
+            }
+
+            let status = child.wait();
+
+            // The readers are left behind if they don't finish in time, the report goes out with what they read
+            let deadline = Instant::now() + OUTPUT_GRACE;
+            for _ in 0..readers {
+                if finished.recv_timeout(deadline.saturating_duration_since(Instant::now())).is_err() {
+                    warning("the command left processes behind that keep its output open, not waiting for the rest");
+                    break;
+                }
+            }
+
+            match status {
+                Ok(status) => describe(status),
+                Err(err) => (format!("failed to wait for the command: {err}"), 1),
+            }
+        }
+        Err(err) => (format!("failed to start: {err}"), 127),
+    };
+
+    let tail = tail.lock().unwrap();
+    Outcome {
+        status,
+        code,
+        output: tail.buf.iter().copied().collect(),
+        dropped: tail.dropped,
+        duration: start.elapsed(),
+    }
+}
+
+/// Fill in the `--subject` template
+fn subject(args: &RunArgs, outcome: &Outcome, host: &str) -> anyhow::Result<String> {
+    let program = args.command[0].rsplit(['/', '\\']).next().unwrap_or_default();
+    let vars = BTreeMap::from([
+        ("status", if outcome.success() { "OK" } else { "FAIL" }.to_string()),
+        ("command", program.to_string()),
+        ("args", command_line(&args.command)),
+        ("host", host.to_string()),
+        ("code", outcome.code.to_string()),
+        ("duration", humantime::format_duration(Duration::from_secs(outcome.duration.as_secs())).to_string()),
+    ]);
+
+    templates::render_subject(&args.subject, &vars)
+}
+
+/// `mailr run`, returns the exit code of the command.
+/// The config is only read after the command ran, and a report that can't be sent is only a warning,
+/// so a broken config or relay never stops the job or hides its exit code.
+pub fn run(args: &RunArgs) -> anyhow::Result<i32> {
+    // Before running anything, so a typo doesn't cost the report
+    templates::check_subject(&args.subject, &SUBJECT_VARS)?;
+
+    let started = Local::now();
+    let outcome = execute(&args.command, args.max_output);
+
+    if args.on.reports(&outcome) {
+        info(format!("'{}' finished with {}, sending the report", args.command[0], outcome.status));
+        if let Err(err) = report(args, &outcome, started) {
+            warning(format!("failed to send the report: {err}"));
+        }
+    }
+
+    Ok(outcome.code)
+}
+
+/// Mail the outcome of the command
+fn report(args: &RunArgs, outcome: &Outcome, started: DateTime<Local>) -> anyhow::Result<()> {
+    let cf = ConfigManager::from_file()?;
+    let host = hostname();
+    let duration = Duration::from_millis(outcome.duration.as_millis() as u64);
+
+    let mut msg = format!(
+        "command  : {}\nhost     : {host}\nstatus   : {}\nstarted  : {}\nduration : {}\n\n",
+        command_line(&args.command),
+        outcome.status,
+        started.format("%Y-%m-%d %H:%M:%S %:z"),
+        humantime::format_duration(duration),
+    );
+
+    let mut attach_data = Vec::new();
+    if outcome.output.is_empty() {
+        msg.push_str("(no output)\n");
+    } else {
+        if outcome.dropped > 0 {
+            msg.push_str(&format!("(the first {} bytes of output were dropped)\n", outcome.dropped));
+        }
+        if outcome.output.len() > INLINE_OUTPUT {
+            msg.push_str(&format!("The output ({} bytes) is attached.\n", outcome.output.len()));
+            attach_data.push(("output.txt".to_string(), outcome.output.clone()));
+        } else {
+            msg.push_str("output:\n\n");
+            msg.push_str(&String::from_utf8_lossy(&outcome.output));
+        }
+    }
+
+    let mail = Args {
+        from: args.from.clone(),
+        to: args.to.clone(),
+        subject: subject(args, outcome, &host)?,
+        msg,
+        attach_data,
+        queue: args.queue,
+        no_signature: true,
+        ..Default::default()
+    };
+
+    send_or_queue(&cf, &mail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(code: i32, output: &[u8]) -> Outcome {
+        Outcome {
+            status: format!("exit code {code}"),
+            code,
+            output: output.to_vec(),
+            dropped: 0,
+            duration: Duration::from_millis(83_400),
+        }
+    }
+
+    fn args(subject: &str, command: &[&str]) -> RunArgs {
+        RunArgs {
+            to: "ops@example.com".to_string(),
+            on: When::Failure,
+            subject: subject.to_string(),
+            from: None,
+            max_output: 1024,
+            queue: false,
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn tail_keeps_the_last_bytes() {
+        let mut tail = Tail {
+            buf: VecDeque::new(),
+            cap: 5,
+            dropped: 0,
+        };
+
+        tail.push(b"abc");
+        assert_eq!((tail.buf.iter().copied().collect::<Vec<_>>(), tail.dropped), (b"abc".to_vec(), 0));
+
+        tail.push(b"defg");
+        tail.push(b"");
+        assert_eq!((tail.buf.iter().copied().collect::<Vec<_>>(), tail.dropped), (b"cdefg".to_vec(), 2));
+
+        tail.push(b"0123456789");
+        assert_eq!((tail.buf.iter().copied().collect::<Vec<_>>(), tail.dropped), (b"56789".to_vec(), 12));
+    }
+
+    #[test]
+    fn command_lines_are_quoted_for_a_shell() {
+        let command = ["/usr/bin/backup", "--dest=/mnt/b", "two words", "", "it's", "$HOME", "a;b"].map(str::to_string);
+
+        assert_eq!(command_line(&command), r#"/usr/bin/backup --dest=/mnt/b 'two words' '' 'it'\''s' '$HOME' 'a;b'"#);
+    }
+
+    #[test]
+    fn subjects_are_rendered_from_the_outcome() {
+        let failed = args(DEFAULT_SUBJECT, &["/usr/local/bin/backup.sh", "--full"]);
+        assert_eq!(subject(&failed, &outcome(2, b""), "db1").unwrap(), "[FAIL] backup.sh on db1");
+
+        let custom = args("{{ status }} {{ args }} ({{ code }}) after {{ duration }}", &["true"]);
+        assert_eq!(subject(&custom, &outcome(0, b""), "db1").unwrap(), "OK true (0) after 1m 23s");
+    }
+
+    #[test]
+    fn reports_are_sent_as_chosen_with_on() {
+        let cases = [
+            (When::Failure, outcome(0, b"out"), false),
+            (When::Failure, outcome(1, b""), true),
+            (When::Always, outcome(0, b""), true),
+            (When::Output, outcome(0, b""), false),
+            (When::Output, outcome(0, b"out"), true),
+            (When::Output, outcome(1, b""), true),
+        ];
+
+        for (on, outcome, reported) in cases {
+            assert_eq!(on.reports(&outcome), reported, "{on:?} with {}", outcome.status);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn background_processes_dont_hold_up_the_report() {
+        let start = Instant::now();
+        let command = ["sh", "-c", "sleep 30 & echo started; exit 3"].map(str::to_string);
+
+        let outcome = execute(&command, 1024);
+
+        assert!(start.elapsed() < Duration::from_secs(10), "{:?}", start.elapsed());
+        assert_eq!((outcome.code, outcome.output.as_slice()), (3, b"started\n".as_slice()));
+    }
+}
@@ -0,0 +1,215 @@
+//! Store for messages that should be sent at a later time.  
+//! Messages are kept encrypted with `Cipher` until they are due,  
+//! `mailr run-due` (or `mailr daemon`) sends them.  
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use colored::Colorize;
+use lettre::{address::Envelope, Message};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ConfigManager,
+    crypto::{self, Cipher},
+    info,
+    mail::SendMail,
+    queue::{self, Outbox},
+    warning,
+};
+
+/// Parse a local date & time like `2026-10-20 09:00`
+pub fn parse_local_time(s: &str) -> anyhow::Result<DateTime<Local>> {
+    const FORMATS: [&str; 3] = ["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"];
+
+    let naive = FORMATS
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .ok_or(anyhow::anyhow!("expected a date like '2026-10-20 09:00', got '{s}'"))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or(anyhow::anyhow!("'{s}' does not exist in the local time zone"))
+}
+
+/// Parse `--send-at`, a local date & time in the future
+pub fn parse_send_at(s: &str) -> anyhow::Result<DateTime<Local>> {
+    let at = parse_local_time(s)?;
+
+    // It would go out with the next `run-due`, which is hardly what was meant
+    if at <= Local::now() {
+        anyhow::bail!("'{s}' is in the past");
+    }
+
+    Ok(at)
+}
+
+/// Parse a delay like `2h` or `1h 30m` for `--delay`
+pub fn parse_delay(s: &str) -> anyhow::Result<Duration> {
+    Ok(humantime::parse_duration(s)?)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduledMail {
+    #[serde(skip)]
+    pub id: String,
+    pub subject: String,
+    /// Time (unix seconds) at which the message should be sent
+    pub due: u64,
+    /// The encrypted raw message
+    message: Vec<u8>,
+    nonce: Vec<u8>,
+    pub envelope: Envelope,
+}
+
+pub struct Schedule {
+    dir: PathBuf,
+}
+
+impl Schedule {
+    /// Open (and create if needed) the schedule store in the data directory
+    pub fn open() -> anyhow::Result<Self> {
+        let dir = ConfigManager::data_dir()?.join("schedule");
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.toml"))
+    }
+
+    /// Store `message` until `due` (unix seconds) and return its id
+    pub fn push(&self, message: &Message, subject: &str, due: u64) -> anyhow::Result<String> {
+        let cipher = Cipher::new();
+
+        let mut encrypted = Vec::new();
+        let nonce = cipher.encrypt(message.formatted(), &mut encrypted)?;
+
+        let entry = ScheduledMail {
+            id: format!("{due:x}-{}", crypto::random_hex(4)),
+            subject: subject.to_string(),
+            due,
+            message: encrypted,
+            nonce: nonce.to_vec(),
+            envelope: message.envelope().clone(),
+        };
+
+        fs::write(self.path(&entry.id), toml::to_string(&entry)?)?;
+        Ok(entry.id)
+    }
+
+    /// All scheduled messages, earliest first
+    pub fn list(&self) -> anyhow::Result<Vec<ScheduledMail>> {
+        let mut entries = Vec::new();
+
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                let id = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                let mut entry: ScheduledMail = toml::from_str(&fs::read_to_string(&path)?)
+                    .map_err(|e| anyhow::anyhow!("failed to read scheduled mail '{}': {e}", path.display()))?;
+                entry.id = id;
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by_key(|entry| entry.due);
+        Ok(entries)
+    }
+
+    /// Remove the scheduled message with `id`
+    pub fn remove(&self, id: &str) -> anyhow::Result<()> {
+        queue::check_id(id)?;
+        let path = self.path(id);
+        if !path.is_file() {
+            anyhow::bail!("no scheduled message with id '{id}'");
+        }
+
+        Ok(fs::remove_file(path)?)
+    }
+
+    /// Send every message that is due.
+    /// Messages that fail to send are moved to the outbox, so they are not lost.
+    /// Messages that can't be decrypted (e.g. after the key changed) are skipped and stay in the schedule.
+    /// Returns the number of messages that were handled.
+    pub fn run_due(&self, cf: &ConfigManager) -> anyhow::Result<usize> {
+        let cipher = Cipher::new();
+        let now = queue::now();
+        let mut handled = 0;
+
+        for entry in self.list()?.into_iter().filter(|entry| entry.due <= now) {
+            info(format!("sending scheduled '{}' ({})", entry.id, entry.subject.as_str().bold()));
+
+            let raw = match cipher.decrypt_bytes(&entry.message, entry.nonce.as_slice().into()) {
+                Ok(raw) => raw,
+                Err(err) => {
+                    warning(format!("skipping '{}', it can't be decrypted: {err}", entry.id));
+                    continue;
+                }
+            };
+
+            if let Err(err) = cf.deliver_raw(&entry.envelope, &raw) {
+                warning(format!("failed to send '{}': {err}", entry.id));
+                let id = Outbox::open()?.push_raw(
+                    &entry.envelope,
+                    &raw,
+                    &entry.subject,
+                    Some(&err),
+                    &cf.config.queue,
+                )?;
+                info(format!("moved '{}' to the outbox as '{id}'", entry.id));
+            }
+
+            self.remove(&entry.id)?;
+            handled += 1;
+        }
+
+        Ok(handled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_at_takes_local_times() {
+        let year = Local::now().format("%Y").to_string().parse::<i32>().unwrap() + 1;
+
+        for s in [format!("{year}-10-20 09:00"), format!("{year}-10-20 09:00:30"), format!("{year}-10-20T09:00")] {
+            let at = parse_send_at(&s).unwrap();
+            assert_eq!(at.format("%Y-%m-%d %H:%M").to_string(), format!("{year}-10-20 09:00"), "{s}");
+        }
+    }
+
+    #[test]
+    fn send_at_rejects_the_past() {
+        let err = parse_send_at("2001-01-01 12:00").unwrap_err();
+        assert!(err.to_string().contains("in the past"), "{err}");
+
+        let a_minute_ago = (Local::now() - chrono::Duration::minutes(1)).format("%Y-%m-%d %H:%M").to_string();
+        assert!(parse_send_at(&a_minute_ago).is_err());
+    }
+
+    #[test]
+    fn send_at_rejects_garbage() {
+        for s in ["", "tomorrow", "2026-13-01 09:00", "2026-10-20", "09:00", "2026-10-20 25:00"] {
+            assert!(parse_send_at(s).is_err(), "{s:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn delays_are_relative_durations() {
+        assert_eq!(parse_delay("2h").unwrap(), Duration::from_secs(2 * 60 * 60));
+        assert_eq!(parse_delay("1h 30m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_delay("45s").unwrap(), Duration::from_secs(45));
+    }
+
+    #[test]
+    fn delays_reject_garbage() {
+        for s in ["", "soon", "2", "-1h", "1x"] {
+            assert!(parse_delay(s).is_err(), "{s:?} was accepted");
+        }
+    }
+}
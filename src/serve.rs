@@ -0,0 +1,723 @@
+//! `mailr serve`: a small SMTP server for applications that can only submit mail to localhost.  
+//! Messages are relayed through the configured relay with the stored login (or put in the outbox),  
+//! so those applications never see the real password. Logins for them are kept in the `[serve]` section.  
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use lettre::{
+    address::Envelope,
+    message::{
+        header::{ContentTransferEncoding, HeaderName, HeaderValue},
+        Body, Mailbox,
+    },
+    Address, Message,
+};
+use mailparse::MailHeaderMap;
+
+use crate::{
+    batch::{BlockingBatch, Refused},
+    config::{ConfigManager, ServeUser},
+    info,
+    mail::SendMail,
+    queue, warning,
+};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:2525";
+
+/// Largest accepted message, announced with the SIZE extension
+const MAX_SIZE: usize = 35 * 1024 * 1024;
+
+const MAX_RECIPIENTS: usize = 100;
+
+/// Longest accepted line, commands and message lines alike
+const MAX_LINE: u64 = 8192;
+
+/// Connections served at the same time, more are turned away with a 421
+const MAX_CONNECTIONS: usize = 32;
+
+/// How long a client may stay silent before it is disconnected
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    #[arg(short, long, value_name("ADDR:PORT"), help("where to listen, 127.0.0.1:2525 if not set here or in the config"))]
+    pub listen: Option<String>,
+    #[arg(long, action, help("put every message in the outbox instead of relaying it right away"))]
+    pub queue: bool,
+    #[arg(long, value_name("NAME"), exclusive(true), help("add or change the login of a local application, then exit"))]
+    pub add_user: Option<String>,
+    #[arg(long, value_name("NAME"), exclusive(true), help("remove the login of a local application, then exit"))]
+    pub remove_user: Option<String>,
+}
+
+/// The address in `FROM:<address> PARAMS` or `TO:<address>`, `None` for the null sender `<>`
+fn parse_path(arg: &str, prefix: &str) -> Result<Option<Address>, &'static str> {
+    let path = match arg.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => arg[prefix.len()..].trim_start(),
+        _ => return Err("501 5.5.4 Syntax error in parameters"),
+    };
+    let path = path.split_whitespace().next().unwrap_or_default();
+    let path = path.strip_prefix('<').and_then(|path| path.strip_suffix('>')).unwrap_or(path);
+
+    if path.is_empty() {
+        return Ok(None);
+    }
+    path.parse().map(Some).map_err(|_| "553 5.1.3 Bad address syntax")
+}
+
+/// The `SIZE=` parameter of MAIL FROM, if any
+fn declared_size(arg: &str) -> Option<usize> {
+    arg.split_whitespace()
+        .skip(1)
+        .find_map(|param| param.get(..5).filter(|key| key.eq_ignore_ascii_case("SIZE=")).and(param.get(5..)))
+        .and_then(|size| size.parse().ok())
+}
+
+/// The message received after DATA, with its headers as they were sent so a signature covers them unchanged
+fn parse_message(envelope: &Envelope, raw: &[u8]) -> anyhow::Result<Message> {
+    let (head, body) = match raw.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => (&raw[..end + 2], &raw[end + 4..]),
+        None => (raw, &b""[..]),
+    };
+    let head = std::str::from_utf8(head).map_err(|_| anyhow::anyhow!("the headers are not valid UTF-8"))?;
+
+    // (name, value as sent, value unfolded)
+    let mut fields: Vec<(&str, String, String)> = Vec::new();
+    for line in head.split_terminator("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            let (_, encoded, unfolded) = fields
+                .last_mut()
+                .ok_or_else(|| anyhow::anyhow!("the headers start with a continuation line"))?;
+            encoded.push_str("\r\n");
+            encoded.push_str(line);
+            unfolded.push_str(line);
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid header line '{line}'"))?;
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        fields.push((name, value.to_string(), value.to_string()));
+    }
+
+    // The builder insists on a From, it is replaced by the headers of the message right away
+    let originator = envelope.from().or(envelope.to().first()).cloned();
+    let originator = originator.ok_or_else(|| anyhow::anyhow!("the envelope has no addresses"))?;
+    let mut message = Message::builder()
+        .from(Mailbox::new(None, originator))
+        .envelope(envelope.clone())
+        .body(Body::dangerous_pre_encoded(body.to_vec(), ContentTransferEncoding::Binary))?;
+
+    let headers = message.headers_mut();
+    headers.clear();
+    for (name, encoded, unfolded) in fields {
+        let name = HeaderName::new_from_ascii(name.to_string())
+            .map_err(|_| anyhow::anyhow!("invalid header name '{name}'"))?;
+        headers.insert_raw(HeaderValue::dangerous_new_pre_encoded(name, unfolded, encoded));
+    }
+    Ok(message)
+}
+
+fn decode(data: &str) -> Option<String> {
+    BASE64.decode(data.trim()).ok().and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+/// A line read from the client
+enum Line {
+    /// Without its line ending, `crlf` tells whether that was `\r\n` rather than a bare `\n`
+    Complete { text: Vec<u8>, crlf: bool },
+    /// Longer than `MAX_LINE`, the whole line has been discarded
+    TooLong { crlf: bool },
+}
+
+/// One SMTP conversation with a client
+struct Session<'a> {
+    cf: &'a ConfigManager,
+    /// Shared by all connections, so the limits of the relay hold for all of them together
+    batch: &'a BlockingBatch<'a>,
+    queue: bool,
+    peer: SocketAddr,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    authenticated: bool,
+    /// The sender of the current transaction, `Some(None)` for the null sender
+    sender: Option<Option<Address>>,
+    recipients: Vec<Address>,
+}
+
+impl<'a> Session<'a> {
+    fn new(cf: &'a ConfigManager, batch: &'a BlockingBatch<'a>, queue: bool, stream: TcpStream) -> io::Result<Self> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(Self {
+            cf,
+            batch,
+            queue,
+            peer: stream.peer_addr()?,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            authenticated: false,
+            sender: None,
+            recipients: Vec::new(),
+        })
+    }
+
+    fn users(&self) -> &[ServeUser] {
+        &self.cf.config.serve.users
+    }
+
+    fn reply(&mut self, reply: &str) -> io::Result<()> {
+        self.writer.write_all(format!("{reply}\r\n").as_bytes())
+    }
+
+    /// The next line, `None` when the client is gone
+    fn read_line(&mut self) -> io::Result<Option<Line>> {
+        let mut text = Vec::new();
+        (&mut self.reader).take(MAX_LINE).read_until(b'\n', &mut text)?;
+        if text.is_empty() {
+            return Ok(None);
+        }
+        if text.len() as u64 == MAX_LINE && text.last() != Some(&b'\n') {
+            // The rest must not be taken for a line of its own, it could be a "." ending the message
+            let crlf = self.skip_line(text.last().copied())?;
+            return Ok(Some(Line::TooLong { crlf }));
+        }
+
+        let crlf = text.ends_with(b"\r\n");
+        if text.last() == Some(&b'\n') {
+            text.truncate(text.len() - if crlf { 2 } else { 1 });
+        }
+        Ok(Some(Line::Complete { text, crlf }))
+    }
+
+    /// Discard the rest of a line, returns whether it ended with `\r\n`
+    fn skip_line(&mut self, mut last: Option<u8>) -> io::Result<bool> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            if let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                let crlf = end.checked_sub(1).map_or(last, |i| Some(buf[i])) == Some(b'\r');
+                self.reader.consume(end + 1);
+                return Ok(crlf);
+            }
+
+            last = buf.last().copied();
+            let len = buf.len();
+            self.reader.consume(len);
+        }
+    }
+
+    /// Ask for a base64 encoded value during AUTH
+    fn challenge(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        self.reply(&format!("334 {}", BASE64.encode(prompt)))?;
+        Ok(self
+            .read_line()?
+            .and_then(|line| match line {
+                Line::Complete { text, .. } => String::from_utf8(text).ok(),
+                Line::TooLong { .. } => None,
+            })
+            .filter(|line| line != "*")
+            .and_then(|line| decode(&line)))
+    }
+
+    fn auth(&mut self, arg: &str) -> io::Result<()> {
+        if self.users().is_empty() {
+            return self.reply("503 5.5.1 AUTH not available");
+        }
+        if self.authenticated {
+            return self.reply("503 5.5.1 Already authenticated");
+        }
+
+        let mut parts = arg.split_whitespace();
+        let mechanism = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let initial = parts.next().map(str::to_string);
+
+        let credentials = match mechanism.as_str() {
+            "PLAIN" => {
+                let response = match initial {
+                    Some(initial) => decode(&initial),
+                    None => self.challenge("")?,
+                };
+                // authorization id \0 username \0 password
+                response.and_then(|response| {
+                    let mut fields = response.splitn(3, '\0').skip(1);
+                    Some((fields.next()?.to_string(), fields.next()?.to_string()))
+                })
+            }
+            "LOGIN" => {
+                let username = match initial {
+                    Some(initial) => decode(&initial),
+                    None => self.challenge("Username:")?,
+                };
+                match username {
+                    Some(username) => self.challenge("Password:")?.map(|password| (username, password)),
+                    None => None,
+                }
+            }
+            _ => return self.reply("504 5.5.4 Unrecognized authentication type"),
+        };
+
+        let valid = credentials.as_ref().is_some_and(|(username, password)| {
+            self.users()
+                .iter()
+                .any(|user| user.username == *username && user.check(password))
+        });
+
+        if valid {
+            self.authenticated = true;
+            self.reply("235 2.7.0 Authentication successful")
+        } else {
+            warning(format!("failed login from {}", self.peer));
+            self.reply("535 5.7.8 Authentication credentials invalid")
+        }
+    }
+
+    /// Read the message after DATA, undoing the dot-stuffing, `None` when the client is gone.
+    /// A message that can't be accepted is still read to the end, its error is the reply for the client.
+    /// Only `\r\n.\r\n` ends the message, a `.` line next to a bare `\n` is part of it.
+    fn data(&mut self) -> io::Result<Option<Result<Vec<u8>, &'static str>>> {
+        self.reply("354 End data with <CR><LF>.<CR><LF>")?;
+
+        let mut raw = Vec::new();
+        let mut first = true;
+        let mut too_long = false;
+        // The DATA command itself ended with one
+        let mut after_crlf = true;
+        loop {
+            let (line, crlf) = match self.read_line()? {
+                None => return Ok(None),
+                Some(Line::TooLong { crlf }) => {
+                    too_long = true;
+                    after_crlf = crlf;
+                    continue;
+                }
+                Some(Line::Complete { text, crlf }) => (text, crlf),
+            };
+            if line == b"." && crlf && after_crlf {
+                break;
+            }
+            after_crlf = crlf;
+
+            // The line break before the final "." belongs to the terminator, which the relay adds again.
+            // Only a period followed by more was added by the client (RFC 5321 4.5.2), a lone one is part of the message
+            if raw.len() <= MAX_SIZE {
+                if !first {
+                    raw.extend_from_slice(b"\r\n");
+                }
+                match line.strip_prefix(b".") {
+                    Some(rest) if !rest.is_empty() => raw.extend_from_slice(rest),
+                    _ => raw.extend_from_slice(&line),
+                }
+            }
+            first = false;
+        }
+
+        Ok(Some(if too_long {
+            Err("500 5.5.2 Line too long")
+        } else if raw.len() > MAX_SIZE {
+            Err("552 5.3.4 Message too big")
+        } else {
+            Ok(raw)
+        }))
+    }
+
+    /// The message as it will be relayed, with a DKIM signature if one is configured
+    fn sign(&self, envelope: &Envelope, raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if self.cf.config.dkim.is_none() {
+            return Ok(raw.to_vec());
+        }
+
+        let mut message = parse_message(envelope, raw)?;
+        self.cf.sign(&mut message)?;
+        Ok(message.formatted())
+    }
+
+    /// Sign and relay (or queue) the message, returns the reply for the client
+    fn deliver(&self, envelope: &Envelope, raw: &[u8]) -> String {
+        let raw = match self.sign(envelope, raw) {
+            Ok(signed) => signed,
+            Err(err) => {
+                warning(format!("failed to sign the message from {}: {err}", self.peer));
+                let reason = err.to_string().replace(['\r', '\n'], " ");
+                return format!("554 5.6.0 Failed to sign the message: {reason}");
+            }
+        };
+        let raw = &raw[..];
+        let subject = mailparse::parse_headers(raw)
+            .ok()
+            .and_then(|(headers, _)| headers.get_first_value("Subject"))
+            .unwrap_or_default();
+
+        if self.queue {
+            let queued = queue::Outbox::open()
+                .and_then(|outbox| outbox.push_raw(envelope, raw, &subject, None, &self.cf.config.queue));
+            return match queued {
+                Ok(id) => {
+                    info(format!("queued message from {} as '{id}'", self.peer));
+                    format!("250 2.0.0 Ok: queued as {id}")
+                }
+                Err(err) => {
+                    warning(format!("failed to queue message from {}: {err}", self.peer));
+                    "451 4.3.0 Failed to queue the message".to_string()
+                }
+            };
+        }
+
+        let delivery = self.batch.send(envelope, raw);
+        let Err(err) = delivery.result() else {
+            info(format!("relayed message from {} to {} recipients", self.peer, envelope.to().len()));
+            return "250 2.0.0 Ok: relayed".to_string();
+        };
+        let retry = delivery.refused(envelope, true);
+        let failed = delivery.refused(envelope, false);
+
+        // Nobody got it and nobody will
+        if delivery.accepted.is_empty() && retry.is_none() {
+            warning(format!("relay rejected message from {}: {err}", self.peer));
+            let reason = err.to_string().replace(['\r', '\n'], " ");
+            return format!("554 5.0.0 Relay rejected the message: {reason}");
+        }
+
+        // Rejecting now would make the client send it again to everyone, so the refused recipients go into
+        // the outbox: to be tried again if their failure is temporary, as failed (for `mailr queue list`) otherwise
+        let push_refused = |refused: Refused| {
+            queue::Outbox::open()
+                .and_then(|outbox| outbox.push_refused(refused, raw, &subject, &self.cf.config.queue))
+        };
+
+        let queued = match retry.map(push_refused) {
+            Some(Ok(id)) => Some(id),
+            Some(Err(queue_err)) if delivery.accepted.is_empty() => {
+                warning(format!("relay failed ({err}) and queueing failed: {queue_err}"));
+                return "451 4.4.0 Relay temporarily unavailable".to_string();
+            }
+            Some(Err(queue_err)) => {
+                warning(format!("relay failed ({err}) for some recipients and queueing failed: {queue_err}"));
+                None
+            }
+            None => None,
+        };
+
+        if let Some(failed) = failed {
+            warning(format!(
+                "relay refused {} of {} recipients of the message from {}: {}",
+                failed.envelope.to().len(),
+                envelope.to().len(),
+                self.peer,
+                failed.reason
+            ));
+            match push_refused(failed) {
+                Ok(id) => info(format!("kept the message for them in the outbox as failed, see '{id}'")),
+                Err(queue_err) => warning(format!("failed to keep the message in the outbox: {queue_err}")),
+            }
+        }
+
+        match queued {
+            Some(id) => {
+                warning(format!("relay failed, queued message from {} as '{id}': {err}", self.peer));
+                format!("250 2.0.0 Ok: queued as {id}")
+            }
+            None => "250 2.0.0 Ok: relayed to some recipients".to_string(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sender = None;
+        self.recipients.clear();
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.reply("220 mailr ESMTP ready")?;
+
+        while let Some(line) = self.read_line()? {
+            let Line::Complete { text, .. } = line else {
+                self.reply("500 5.5.2 Line too long")?;
+                continue;
+            };
+            let line = String::from_utf8_lossy(&text).to_string();
+            let (verb, arg) = line.split_once(' ').unwrap_or((&line, ""));
+            let arg = arg.trim();
+
+            match verb.to_ascii_uppercase().as_str() {
+                "EHLO" => {
+                    self.reset();
+                    let mut lines = vec!["mailr".to_string(), format!("SIZE {MAX_SIZE}"), "8BITMIME".to_string()];
+                    if !self.users().is_empty() {
+                        lines.push("AUTH PLAIN LOGIN".to_string());
+                    }
+                    let last = lines.len() - 1;
+                    for (i, line) in lines.iter().enumerate() {
+                        let separator = if i == last { ' ' } else { '-' };
+                        self.reply(&format!("250{separator}{line}"))?;
+                    }
+                }
+                "HELO" => {
+                    self.reset();
+                    self.reply("250 mailr")?;
+                }
+                "AUTH" => self.auth(arg)?,
+                "MAIL" if !self.users().is_empty() && !self.authenticated => {
+                    self.reply("530 5.7.0 Authentication required")?
+                }
+                "MAIL" if self.sender.is_some() => self.reply("503 5.5.1 Sender already given")?,
+                "MAIL" => match parse_path(arg, "FROM:") {
+                    Ok(_) if declared_size(arg).is_some_and(|size| size > MAX_SIZE) => {
+                        self.reply("552 5.3.4 Message too big")?
+                    }
+                    Ok(sender) => {
+                        self.sender = Some(sender);
+                        self.reply("250 2.1.0 Ok")?;
+                    }
+                    Err(reply) => self.reply(reply)?,
+                },
+                "RCPT" if self.sender.is_none() => self.reply("503 5.5.1 Need MAIL before RCPT")?,
+                "RCPT" if self.recipients.len() >= MAX_RECIPIENTS => self.reply("452 4.5.3 Too many recipients")?,
+                "RCPT" => match parse_path(arg, "TO:") {
+                    Ok(Some(recipient)) => {
+                        self.recipients.push(recipient);
+                        self.reply("250 2.1.5 Ok")?;
+                    }
+                    Ok(None) => self.reply("553 5.1.3 Recipient address required")?,
+                    Err(reply) => self.reply(reply)?,
+                },
+                "DATA" if self.recipients.is_empty() => self.reply("503 5.5.1 Need RCPT before DATA")?,
+                "DATA" => {
+                    let Some(data) = self.data()? else {
+                        return Ok(());
+                    };
+                    let sender = self.sender.clone().flatten();
+                    let reply = match (data, Envelope::new(sender, self.recipients.clone())) {
+                        (Err(reply), _) => reply.to_string(),
+                        (Ok(raw), Ok(envelope)) => self.deliver(&envelope, &raw),
+                        (Ok(_), Err(err)) => format!("554 5.5.0 Invalid envelope: {err}"),
+                    };
+                    self.reply(&reply)?;
+                    self.reset();
+                }
+                "RSET" => {
+                    self.reset();
+                    self.reply("250 2.0.0 Ok")?;
+                }
+                "NOOP" => self.reply("250 2.0.0 Ok")?,
+                "VRFY" => self.reply("252 2.5.0 Cannot verify the user, but will try delivery")?,
+                "QUIT" => {
+                    self.reply("221 2.0.0 Bye")?;
+                    return Ok(());
+                }
+                _ => self.reply("502 5.5.2 Command not recognized")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn add_user(cf: &mut ConfigManager, name: &str) -> anyhow::Result<()> {
+    let password = inquire::Password::new(&format!("password for '{name}':")).prompt()?;
+
+    let users = &mut cf.config.serve.users;
+    users.retain(|user| user.username != name);
+    users.push(ServeUser::new(name, &password)?);
+    cf.update()?;
+
+    info(format!("saved the login '{name}', local applications now have to log in"));
+    Ok(())
+}
+
+fn remove_user(cf: &mut ConfigManager, name: &str) -> anyhow::Result<()> {
+    let users = &mut cf.config.serve.users;
+    let before = users.len();
+    users.retain(|user| user.username != name);
+    if users.len() == before {
+        anyhow::bail!("there is no login named '{name}'");
+    }
+    cf.update()?;
+
+    info(format!("removed the login '{name}'"));
+    Ok(())
+}
+
+/// `mailr serve`, runs until it is stopped
+pub fn serve(cf: &mut ConfigManager, args: &ServeArgs) -> anyhow::Result<()> {
+    if let Some(name) = &args.add_user {
+        return add_user(cf, name);
+    }
+    if let Some(name) = &args.remove_user {
+        return remove_user(cf, name);
+    }
+
+    let listen = args
+        .listen
+        .clone()
+        .or(cf.config.serve.listen.clone())
+        .unwrap_or(DEFAULT_LISTEN.to_string());
+    let listener = TcpListener::bind(&listen).map_err(|e| anyhow::anyhow!("failed to listen on '{listen}': {e}"))?;
+
+    if cf.config.serve.users.is_empty() && !listener.local_addr()?.ip().is_loopback() {
+        warning(format!(
+            "no logins configured, anyone who can reach {listen} can send mail as you (add one with --add-user)"
+        ));
+    }
+    info(format!("accepting mail on {listen}, press CTRL-C to stop"));
+
+    let cf = &*cf;
+    let batch = &BlockingBatch::new(cf)?;
+    let connections = &AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warning(format!("failed to accept a connection: {err}"));
+                    continue;
+                }
+            };
+
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warning(format!("more than {MAX_CONNECTIONS} connections, turned one away"));
+                let _ = (&stream).write_all(b"421 4.3.2 Too many connections, try again later\r\n");
+                continue;
+            }
+
+            scope.spawn(move || {
+                let result = Session::new(cf, batch, args.queue, stream).and_then(|mut session| session.run());
+
+                match result {
+                    Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                        warning("a client stayed silent for too long, disconnected it")
+                    }
+                    Err(err) => warning(format!("connection failed: {err}")),
+                    Ok(()) => {}
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::test_support::{accepting_relay, config};
+
+    /// Send `input` to a session relaying to a fake relay, returns the replies and the relayed messages
+    fn converse(input: &[u8]) -> (Vec<String>, Vec<Vec<u8>>) {
+        let (relay_port, relayed) = accepting_relay();
+        let cf = config(relay_port);
+        let batch = BlockingBatch::new(&cf).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let replies = thread::scope(|scope| {
+            let session = scope.spawn(|| Session::new(&cf, &batch, false, stream).and_then(|mut session| session.run()));
+
+            client.write_all(input).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut replies = String::new();
+            client.read_to_string(&mut replies).unwrap();
+
+            session.join().unwrap().unwrap();
+            replies
+        });
+        drop(batch);
+
+        let replies = replies.lines().map(str::to_string).collect();
+        (replies, relayed.try_iter().collect())
+    }
+
+    const ENVELOPE: &[u8] = b"HELO client\r\nMAIL FROM:<app@example.com>\r\nRCPT TO:<to@example.com>\r\nDATA\r\n";
+
+    #[test]
+    fn relays_the_message_as_it_was_sent() {
+        let input = [ENVELOPE, b"Subject: hi\r\n\r\nfirst\r\n..dotted\r\n.\r\nQUIT\r\n"].concat();
+        let (replies, relayed) = converse(&input);
+
+        assert_eq!(
+            replies,
+            [
+                "220 mailr ESMTP ready",
+                "250 mailr",
+                "250 2.1.0 Ok",
+                "250 2.1.5 Ok",
+                "354 End data with <CR><LF>.<CR><LF>",
+                "250 2.0.0 Ok: relayed",
+                "221 2.0.0 Bye",
+            ]
+        );
+        // Unstuffed once, and without a blank line added at the end
+        assert_eq!(relayed, [b"Subject: hi\r\n\r\nfirst\r\n.dotted\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn dot_lines_next_to_bare_line_feeds_are_part_of_the_message() {
+        let input = [ENVELOPE, b"Subject: hi\r\n\r\na\n.\nb\r\n.\r\nQUIT\r\n"].concat();
+        let (replies, relayed) = converse(&input);
+
+        assert_eq!(replies[5], "250 2.0.0 Ok: relayed");
+        assert_eq!(relayed, [b"Subject: hi\r\n\r\na\r\n.\r\nb\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn commands_out_of_order_are_refused() {
+        let input = b"HELO client\r\nRCPT TO:<to@example.com>\r\nDATA\r\nMAIL FROM:<app@example.com>\r\n\
+                      MAIL FROM:<other@example.com>\r\nDATA\r\nRCPT TO:<>\r\nRSET\r\nRCPT TO:<to@example.com>\r\nFOO\r\n";
+        let (replies, relayed) = converse(input);
+
+        assert_eq!(
+            replies[1..],
+            [
+                "250 mailr",
+                "503 5.5.1 Need MAIL before RCPT",
+                "503 5.5.1 Need RCPT before DATA",
+                "250 2.1.0 Ok",
+                "503 5.5.1 Sender already given",
+                "503 5.5.1 Need RCPT before DATA",
+                "553 5.1.3 Recipient address required",
+                "250 2.0.0 Ok",
+                "503 5.5.1 Need MAIL before RCPT",
+                "502 5.5.2 Command not recognized",
+            ]
+        );
+        assert!(relayed.is_empty());
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let line = [&[b'x'; 998][..], b"\r\n"].concat();
+        let body = line.repeat(MAX_SIZE / line.len() + 2);
+        let input = [ENVELOPE, b"Subject: big\r\n\r\n", &body, b".\r\nNOOP\r\n"].concat();
+        let (replies, relayed) = converse(&input);
+
+        assert_eq!(replies[5..], ["552 5.3.4 Message too big", "250 2.0.0 Ok"]);
+        assert!(relayed.is_empty());
+    }
+
+    #[test]
+    fn oversized_declarations_and_lines_are_refused() {
+        let long_line = vec![b'x'; MAX_LINE as usize * 2];
+        let input = [
+            format!("HELO client\r\nMAIL FROM:<app@example.com> SIZE={}\r\n", MAX_SIZE + 1).as_bytes(),
+            &long_line,
+            b"\r\nNOOP\r\n",
+        ]
+        .concat();
+        let (replies, _) = converse(&input);
+
+        assert_eq!(replies[2..], ["552 5.3.4 Message too big", "500 5.5.2 Line too long", "250 2.0.0 Ok"]);
+    }
+}
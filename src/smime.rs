@@ -0,0 +1,427 @@
+//! S/MIME signing and encryption of messages (RFC 8551), for `--smime`.  
+//! The own certificate and key are configured in the `[smime]` section, as PEM/DER files or a PKCS#12 bundle,  
+//! certificates of recipients are looked up by address in the certificate store directory.  
+
+use std::{fs, path::Path};
+
+use lettre::message::{header::ContentType, Attachment, MultiPart, SinglePart};
+use lettre::Address;
+use openssl::{
+    asn1::Asn1Time,
+    nid::Nid,
+    pkcs12::Pkcs12,
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    symm,
+    x509::X509,
+};
+
+use crate::{
+    config::{ConfigManager, SmimeSettings},
+    mail::Content,
+};
+
+/// The own certificate, the rest of its chain and the private key
+struct Signer {
+    certificate: X509,
+    chain: Stack<X509>,
+    key: PKey<Private>,
+}
+
+/// All certificates in the PEM or DER file at `path`
+fn read_certificates(path: &Path) -> anyhow::Result<Vec<X509>> {
+    let data = fs::read(path).map_err(|e| anyhow::anyhow!("failed to read certificate '{}': {e}", path.display()))?;
+    let certificates = match data.windows(11).any(|window| window == b"-----BEGIN ") {
+        true => X509::stack_from_pem(&data),
+        false => X509::from_der(&data).map(|certificate| vec![certificate]),
+    };
+
+    certificates.map_err(|e| anyhow::anyhow!("failed to parse certificate '{}': {e}", path.display()))
+}
+
+/// Whether `certificate` was issued for `address`, in the subject alternative names or the subject
+fn has_address(certificate: &X509, address: &Address) -> bool {
+    let alt_names: Vec<String> = certificate
+        .subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.email().map(str::to_string))
+        .collect();
+    let subject = certificate
+        .subject_name()
+        .entries_by_nid(Nid::PKCS9_EMAILADDRESS)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|email| email.to_string()));
+
+    alt_names
+        .into_iter()
+        .chain(subject)
+        .any(|email| email.eq_ignore_ascii_case(address.as_ref()))
+}
+
+fn is_expired(certificate: &X509) -> bool {
+    Asn1Time::days_from_now(0).is_ok_and(|now| certificate.not_after() < now)
+}
+
+/// The stored password, or asked for when the key turns out to be protected
+fn ask_password(settings: &SmimeSettings) -> anyhow::Result<String> {
+    match settings.password()? {
+        Some(password) => Ok(password),
+        None => Ok(inquire::Password::new("password of the S/MIME key:")
+            .without_confirmation()
+            .prompt()?),
+    }
+}
+
+impl SmimeSettings {
+    fn is_bundle(&self) -> bool {
+        self.certificate
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"))
+    }
+
+    /// The own certificate, to encrypt to. Unlike `signer` it doesn't need the key, or its password
+    /// (only a bundle protected by a password that isn't stored has to be opened with the prompt).
+    fn own_certificate(&self) -> anyhow::Result<X509> {
+        if self.is_bundle() {
+            return Ok(self.bundle_signer()?.certificate);
+        }
+
+        read_certificates(&self.certificate)?
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("no certificate in '{}'", self.certificate.display()))
+    }
+
+    fn signer(&self) -> anyhow::Result<Signer> {
+        if self.is_bundle() {
+            return self.bundle_signer();
+        }
+
+        let mut certificates = read_certificates(&self.certificate)?.into_iter();
+        let certificate = certificates
+            .next()
+            .ok_or(anyhow::anyhow!("no certificate in '{}'", self.certificate.display()))?;
+        let mut chain = Stack::new()?;
+        for certificate in certificates {
+            chain.push(certificate)?;
+        }
+
+        let path = self
+            .key
+            .as_ref()
+            .ok_or(anyhow::anyhow!("no private key configured, set 'key' in the [smime] section"))?;
+        let data = fs::read(path).map_err(|e| anyhow::anyhow!("failed to read key '{}': {e}", path.display()))?;
+
+        let key = if !data.starts_with(b"-----") {
+            PKey::private_key_from_der(&data)?
+        } else if String::from_utf8_lossy(&data).contains("ENCRYPTED") {
+            PKey::private_key_from_pem_passphrase(&data, ask_password(self)?.as_bytes())?
+        } else {
+            PKey::private_key_from_pem(&data)?
+        };
+
+        Ok(Signer { certificate, chain, key })
+    }
+
+    fn bundle_signer(&self) -> anyhow::Result<Signer> {
+        let path = &self.certificate;
+        let data = fs::read(path).map_err(|e| anyhow::anyhow!("failed to read bundle '{}': {e}", path.display()))?;
+        let bundle = Pkcs12::from_der(&data).map_err(|e| anyhow::anyhow!("failed to parse bundle '{}': {e}", path.display()))?;
+
+        // Bundles without a password are rare, but don't need a prompt
+        let parsed = match (self.password()?, bundle.parse2("")) {
+            (None, Ok(parsed)) => parsed,
+            _ => bundle
+                .parse2(&ask_password(self)?)
+                .map_err(|e| anyhow::anyhow!("failed to open bundle '{}' (wrong password?): {e}", path.display()))?,
+        };
+
+        match (parsed.cert, parsed.pkey) {
+            (Some(certificate), Some(key)) => Ok(Signer {
+                certificate,
+                chain: match parsed.ca {
+                    Some(chain) => chain,
+                    None => Stack::new()?,
+                },
+                key,
+            }),
+            _ => anyhow::bail!("bundle '{}' doesn't hold a certificate and key", path.display()),
+        }
+    }
+}
+
+impl ConfigManager {
+    fn smime_settings(&self) -> anyhow::Result<&SmimeSettings> {
+        self.config
+            .smime
+            .as_ref()
+            .ok_or(anyhow::anyhow!("S/MIME isn't configured, add an [smime] section or run --configure"))
+    }
+
+    /// The certificate of `address` in the certificate store, expired certificates are skipped
+    fn smime_certificate(&self, address: &Address) -> anyhow::Result<X509> {
+        let store = match &self.smime_settings()?.certificates {
+            Some(store) => store.clone(),
+            None => ConfigManager::data_dir()?.join("certs"),
+        };
+
+        if store.is_dir() {
+            for file in fs::read_dir(&store)? {
+                let path = file?.path();
+                let is_certificate = path
+                    .extension()
+                    .is_some_and(|ext| ext == "pem" || ext == "crt" || ext == "cer" || ext == "der");
+                if !is_certificate {
+                    continue;
+                }
+
+                if let Some(certificate) = read_certificates(&path)?
+                    .into_iter()
+                    .find(|certificate| has_address(certificate, address) && !is_expired(certificate))
+                {
+                    return Ok(certificate);
+                }
+            }
+        }
+
+        anyhow::bail!("no valid certificate for '{address}' in '{}'", store.display())
+    }
+
+    /// Sign and/or encrypt `content` for `recipients`.
+    /// The certificates are loaded first, so a missing one fails before anything is sent.
+    pub fn smime_protect(
+        &self,
+        content: Content,
+        sign: bool,
+        encrypt: bool,
+        recipients: &[Address],
+    ) -> anyhow::Result<Content> {
+        let settings = self.smime_settings()?;
+        let signer = match sign {
+            true => Some(settings.signer()?),
+            false => None,
+        };
+
+        let mut certificates = Stack::new()?;
+        if encrypt {
+            for address in recipients {
+                certificates.push(self.smime_certificate(address)?)?;
+            }
+            // Encrypting needs no private key, so its password is only asked for to sign
+            if settings.encrypt_to_self {
+                match &signer {
+                    Some(signer) => certificates.push(signer.certificate.clone())?,
+                    None => certificates.push(settings.own_certificate()?)?,
+                }
+            }
+        }
+
+        let content = match (&signer, sign) {
+            (Some(signer), true) => Content::Multi(sign_content(content, signer)?),
+            _ => content,
+        };
+
+        match encrypt {
+            true => Ok(Content::Single(encrypt_content(content, &certificates)?)),
+            false => Ok(content),
+        }
+    }
+}
+
+/// multipart/signed with a detached PKCS#7 signature over the canonical form of `content`
+fn sign_content(content: Content, signer: &Signer) -> anyhow::Result<MultiPart> {
+    // The line break before the boundary belongs to the boundary, not to the signed part
+    let canonical = content.canonical()?;
+    let signed_data = canonical.strip_suffix(b"\r\n").unwrap_or(&canonical);
+
+    let signature = Pkcs7::sign(
+        &signer.certificate,
+        &signer.key,
+        &signer.chain,
+        signed_data,
+        Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+    )
+    .map_err(|e| anyhow::anyhow!("failed to sign the message: {e}"))?;
+
+    let signature = Attachment::new("smime.p7s".to_string()).body(
+        signature.to_der()?,
+        ContentType::parse("application/pkcs7-signature; name=\"smime.p7s\"")?,
+    );
+
+    let multipart = MultiPart::signed("application/pkcs7-signature".to_string(), "sha-256".to_string());
+    Ok(content.add_to(multipart).singlepart(signature))
+}
+
+/// application/pkcs7-mime enveloped data holding `content`, readable by every certificate in `certificates`
+fn encrypt_content(content: Content, certificates: &Stack<X509>) -> anyhow::Result<SinglePart> {
+    let encrypted = Pkcs7::encrypt(
+        certificates,
+        &content.canonical()?,
+        symm::Cipher::aes_256_cbc(),
+        Pkcs7Flags::BINARY,
+    )
+    .map_err(|e| anyhow::anyhow!("failed to encrypt the message: {e}"))?;
+
+    Ok(Attachment::new("smime.p7m".to_string()).body(
+        encrypted.to_der()?,
+        ContentType::parse("application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"")?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use openssl::{
+        asn1::Asn1Integer,
+        bn::BigNum,
+        hash::MessageDigest,
+        rsa::Rsa,
+        symm::Cipher,
+        x509::{extension::SubjectAlternativeName, store::X509StoreBuilder, X509NameBuilder},
+    };
+
+    use super::*;
+    use crate::test_support::config_with;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// A throwaway self-signed certificate for `address`, valid from `from` to `to` (unix seconds)
+    fn generate(address: &str, from: i64, to: i64) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, address).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(1).unwrap()).unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::from_unix(from).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::from_unix(to).unwrap()).unwrap();
+        let alt_names = SubjectAlternativeName::new().email(address).build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    fn now() -> i64 {
+        crate::queue::now() as i64
+    }
+
+    fn valid(address: &str) -> (X509, PKey<Private>) {
+        generate(address, now() - DAY, now() + 30 * DAY)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mailr-test-{}-smime-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A config with `own` and its key, protected by `password` if there is one, and `recipients` in the certificate store
+    fn config(name: &str, own: &(X509, PKey<Private>), password: Option<&str>, recipients: &[&X509]) -> ConfigManager {
+        let dir = temp_dir(name);
+        let certificate = dir.join("own.pem");
+        fs::write(&certificate, own.0.to_pem().unwrap()).unwrap();
+        let key = dir.join("own.key");
+        let pem = match password {
+            Some(password) => own.1.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), password.as_bytes()),
+            None => own.1.private_key_to_pem_pkcs8(),
+        };
+        fs::write(&key, pem.unwrap()).unwrap();
+
+        let store = dir.join("certs");
+        fs::create_dir_all(&store).unwrap();
+        for (i, recipient) in recipients.iter().enumerate() {
+            fs::write(store.join(format!("{i}.pem")), recipient.to_pem().unwrap()).unwrap();
+        }
+
+        config_with(
+            25,
+            &format!(
+                "[smime]\ncertificate = '{}'\nkey = '{}'\ncertificates = '{}'\nencrypt_to_self = true\n",
+                certificate.display(),
+                key.display(),
+                store.display()
+            ),
+        )
+    }
+
+    fn content() -> Content {
+        Content::Single(SinglePart::plain("hello\r\n-- \r\nme\r\n".to_string()))
+    }
+
+    fn address(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn signature_verifies_over_the_canonical_content() {
+        let own = valid("me@example.com");
+        let cf = config("sign", &own, None, &[]);
+
+        let formatted = match cf.smime_protect(content(), true, false, &[]).unwrap() {
+            Content::Multi(multipart) => multipart.formatted(),
+            Content::Single(_) => panic!("a signed message is multipart/signed"),
+        };
+        let mail = mailparse::parse_mail(&formatted).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/signed");
+        assert_eq!(mail.subparts[0].raw_bytes, content().formatted().strip_suffix(b"\r\n").unwrap());
+
+        let signature = Pkcs7::from_der(&mail.subparts[1].get_body_raw().unwrap()).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(own.0.clone()).unwrap();
+        let store = store.build();
+        let certificates = Stack::new().unwrap();
+
+        let canonical = content().canonical().unwrap();
+        let signed_data = canonical.strip_suffix(b"\r\n").unwrap();
+        signature
+            .verify(&certificates, &store, Some(signed_data), None, Pkcs7Flags::BINARY)
+            .unwrap();
+        assert!(signature
+            .verify(&certificates, &store, Some(&[signed_data, b"x"].concat()), None, Pkcs7Flags::BINARY)
+            .is_err());
+    }
+
+    #[test]
+    fn encrypted_content_decrypts_for_the_recipient_and_the_sender() {
+        // Encrypting to the own certificate needs no key, with a protected one the prompt would fail here
+        let own = valid("me@example.com");
+        let recipient = valid("to@example.com");
+        let cf = config("encrypt", &own, Some("secret"), &[&recipient.0]);
+
+        let encrypted = match cf.smime_protect(content(), false, true, &[address("to@example.com")]).unwrap() {
+            Content::Single(part) => part.formatted(),
+            Content::Multi(_) => panic!("an encrypted message is application/pkcs7-mime"),
+        };
+        let part = mailparse::parse_mail(&encrypted).unwrap();
+        assert_eq!(part.ctype.mimetype, "application/pkcs7-mime");
+        let enveloped = Pkcs7::from_der(&part.get_body_raw().unwrap()).unwrap();
+
+        for (certificate, key) in [&recipient, &own] {
+            let decrypted = enveloped.decrypt(key, certificate, Pkcs7Flags::BINARY).unwrap();
+            assert_eq!(decrypted, content().canonical().unwrap());
+        }
+    }
+
+    #[test]
+    fn expired_and_missing_certificates_fail_up_front() {
+        let own = valid("me@example.com");
+        let expired = generate("old@example.com", now() - 30 * DAY, now() - DAY);
+        let cf = config("expired", &own, None, &[&expired.0]);
+
+        for to in ["old@example.com", "nobody@example.com"] {
+            let Err(err) = cf.smime_protect(content(), true, true, &[address(to)]) else {
+                panic!("encrypted to '{to}'");
+            };
+            assert!(err.to_string().contains(&format!("no valid certificate for '{to}'")), "{err}");
+        }
+    }
+}
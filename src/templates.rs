@@ -0,0 +1,208 @@
+//! Reusable message templates.  
+//! Templates are TOML files in the `templates` directory next to the global config,  
+//! with default recipients, a subject and a body containing `{{var}}` placeholders.  
+
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use colored::Colorize;
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Deserialize;
+
+use crate::{config::ConfigManager, Args};
+
+#[derive(Debug, Deserialize)]
+pub struct Template {
+    /// Default recipients, used when `--to` is not given
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Parse a `KEY=VALUE` pair for `--var`
+pub fn parse_var(s: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(anyhow::anyhow!("expected KEY=VALUE, got '{s}'"))?;
+
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+/// The directory holding all templates
+pub fn dir() -> anyhow::Result<PathBuf> {
+    let dir = ConfigManager::data_dir()?.join("templates");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Names of all available templates
+pub fn list() -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    for file in fs::read_dir(dir()?)? {
+        let path = file?.path();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            names.push(path.file_stem().unwrap_or_default().to_string_lossy().to_string());
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Template names are file names in the templates directory, they must not reach outside of it (e.g. `../x`)
+pub fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains(['/', '\\', '\0']) || name.contains("..") {
+        anyhow::bail!("'{name}' is not a valid template name");
+    }
+    Ok(())
+}
+
+/// Path of the template called `name`
+pub fn path(name: &str) -> anyhow::Result<PathBuf> {
+    check_name(name)?;
+    let path = dir()?.join(format!("{name}.toml"));
+    if !path.is_file() {
+        anyhow::bail!("no template named '{name}' (looked for '{}')", path.display());
+    }
+    Ok(path)
+}
+
+pub fn load(name: &str) -> anyhow::Result<Template> {
+    let path = path(name)?;
+    toml::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| anyhow::anyhow!("failed to read template '{}': {e}", path.display()))
+}
+
+/// Fill in the recipient, subject and body of `args` from `--template` and `--var`.
+/// Every placeholder in the template has to be set, undefined variables are an error.
+pub fn apply(args: &mut Args) -> anyhow::Result<()> {
+    let Some(name) = args.template.clone() else {
+        return Ok(());
+    };
+    fill(args, &name, &load(&name)?)
+}
+
+/// The part of `apply` after `template` called `name` was loaded
+fn fill(args: &mut Args, name: &str, template: &Template) -> anyhow::Result<()> {
+    let vars: BTreeMap<String, String> = args.var.iter().cloned().collect();
+
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+
+    let render = |field: &str, source: &str| -> anyhow::Result<String> {
+        let tmpl = env
+            .template_from_str(source)
+            .map_err(|e| anyhow::anyhow!("failed to parse {field} of template '{}': {e}", name.bold()))?;
+
+        let mut undefined: Vec<String> = tmpl
+            .undeclared_variables(false)
+            .into_iter()
+            .filter(|var| !vars.contains_key(var))
+            .collect();
+        if !undefined.is_empty() {
+            undefined.sort();
+            anyhow::bail!("undefined variables in {field} of template '{}': {}", name.bold(), undefined.join(", "));
+        }
+
+        tmpl.render(&vars)
+            .map_err(|e| anyhow::anyhow!("failed to render {field} of template '{}': {e}", name.bold()))
+    };
+
+    // Explicitly passed values win over the template
+    if args.subject.is_empty() {
+        args.subject = render("subject", &template.subject)?;
+    }
+    if args.msg.is_empty() {
+        args.msg = render("body", &template.body)?;
+    }
+    if args.to.is_empty() {
+        args.to = template.to.join(", ");
+    }
+    if args.to.is_empty() {
+        anyhow::bail!("template '{name}' has no default recipients, please pass --to");
+    }
+
+    Ok(())
+}
+
+/// Check that the subject template `source` only uses the variables in `known`,
+/// so mistakes show up before there are values to fill in
+pub fn check_subject(source: &str, known: &[&str]) -> anyhow::Result<()> {
+    let env = Environment::new();
+    let template = env
+        .template_from_str(source)
+        .map_err(|e| anyhow::anyhow!("failed to parse the subject '{source}': {e}"))?;
+
+    let mut undefined: Vec<String> = template
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|var| !known.contains(&var.as_str()))
+        .collect();
+    if !undefined.is_empty() {
+        undefined.sort();
+        anyhow::bail!("undefined variables in the subject: {}", undefined.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Fill in the subject template `source`, see `check_subject`
+pub fn render_subject(source: &str, vars: &BTreeMap<&str, String>) -> anyhow::Result<String> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.render_str(source, vars)
+        .map_err(|e| anyhow::anyhow!("failed to render the subject '{source}': {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_checked() {
+        for name in ["welcome", "weekly-report", "v1.2"] {
+            assert!(check_name(name).is_ok(), "{name:?} was rejected");
+        }
+        for name in ["", "../x", "..", "a/b", "a\\b", "/etc/passwd"] {
+            assert!(check_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+    fn template() -> Template {
+        Template {
+            to: vec!["ops@example.com".to_string()],
+            subject: "Deploy of {{ service }} {{ version }}".to_string(),
+            body: "{{ service }} is live on {{ host }}, ask {{ owner }} or {{ backup }}".to_string(),
+        }
+    }
+
+    fn args(vars: &[&str]) -> Args {
+        Args {
+            template: Some("deploy".to_string()),
+            var: vars.iter().map(|var| parse_var(var).unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn templates_are_filled_in() {
+        let mut args = args(&["service=api", "version=1.4", "host=db1", "owner=ann", "backup=bob"]);
+        args.subject = "Explicit subject".to_string();
+
+        fill(&mut args, "deploy", &template()).unwrap();
+
+        assert_eq!(args.to, "ops@example.com");
+        assert_eq!(args.subject, "Explicit subject");
+        assert_eq!(args.msg, "api is live on db1, ask ann or bob");
+    }
+
+    #[test]
+    fn undefined_variables_are_listed_sorted() {
+        let mut args = args(&["service=api", "version=1.4"]);
+
+        let err = fill(&mut args, "deploy", &template()).unwrap_err().to_string();
+
+        assert!(err.starts_with("undefined variables in body of template '"), "{err}");
+        assert!(err.ends_with("': backup, host, owner"), "{err}");
+    }
+}
@@ -0,0 +1,133 @@
+//! Fixtures for the tests: fake SMTP and IMAP servers on a local port, and a config that uses them.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+};
+
+use crate::config::ConfigManager;
+
+/// A relay that answers each `RCPT TO` line (upper-cased) with `rcpt_reply` and everything else with success.
+/// It accepts any number of connections, and sends the messages it gets, dot-unstuffed, to the returned channel.
+pub fn fake_relay(rcpt_reply: fn(&str) -> &'static str) -> (u16, mpsc::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (messages, received) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let messages = messages.clone();
+
+            thread::spawn(move || {
+                writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+                let mut line = Vec::new();
+                while reader.read_until(b'\n', &mut line).unwrap_or_default() > 0 {
+                    let command = String::from_utf8_lossy(&line).to_uppercase();
+                    let reply = match command {
+                        _ if command.starts_with("EHLO") => "250-fake\r\n250 AUTH PLAIN LOGIN",
+                        _ if command.starts_with("AUTH") => "235 ok",
+                        _ if command.starts_with("RCPT") => rcpt_reply(&command),
+                        _ if command.starts_with("DATA") => {
+                            writer.write_all(b"354 go ahead\r\n").unwrap();
+                            let mut message = Vec::new();
+                            loop {
+                                line.clear();
+                                if reader.read_until(b'\n', &mut line).unwrap_or_default() == 0 || line == b".\r\n" {
+                                    break;
+                                }
+                                message.extend_from_slice(line.strip_prefix(b".").unwrap_or(&line));
+                            }
+                            // Nobody may be waiting for the messages
+                            let _ = messages.send(message);
+                            "250 queued"
+                        }
+                        _ if command.starts_with("QUIT") => "221 bye",
+                        _ => "250 ok",
+                    };
+                    if writer.write_all(format!("{reply}\r\n").as_bytes()).is_err() {
+                        return;
+                    }
+                    line.clear();
+                }
+            });
+        }
+    });
+
+    (port, received)
+}
+
+/// A relay that accepts everything, see `fake_relay`
+pub fn accepting_relay() -> (u16, mpsc::Receiver<Vec<u8>>) {
+    fake_relay(|_| "250 ok")
+}
+
+/// A minimal IMAP server that accepts one connection and returns the commands it got
+pub fn fake_imap() -> (u16, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut commands = Vec::new();
+
+        writer.write_all(b"* OK fake IMAP ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                return commands;
+            }
+            let line = line.trim_end().to_string();
+            let (tag, command) = line.split_once(' ').unwrap();
+
+            // `APPEND "Sent" (\Seen) {42}`: the message follows as a literal of that size
+            if let Some(size) = command.strip_suffix('}').and_then(|rest| rest.rsplit_once('{')) {
+                writer.write_all(b"+ ready for literal\r\n").unwrap();
+                let mut literal = vec![0; size.1.parse().unwrap()];
+                reader.read_exact(&mut literal).unwrap();
+                reader.read_line(&mut String::new()).unwrap();
+            }
+
+            writer.write_all(format!("{tag} OK done\r\n").as_bytes()).unwrap();
+            commands.push(command.to_string());
+        }
+    });
+
+    (port, server)
+}
+
+/// A config that relays to `relay_port` without TLS and keeps no history
+pub fn config(relay_port: u16) -> ConfigManager {
+    config_with(relay_port, "")
+}
+
+/// `config` with more `sections`, in TOML
+pub fn config_with(relay_port: u16, sections: &str) -> ConfigManager {
+    toml::from_str(&format!(
+        r#"
+        [login]
+        username = "me@example.com"
+        password = []
+        nonce = []
+
+        [relay]
+        addr = "127.0.0.1"
+        port = {relay_port}
+        tls = false
+        authentication = ["Plain"]
+
+        [history]
+        enabled = false
+        store_bodies = false
+
+        {sections}
+        "#
+    ))
+    .unwrap()
+}
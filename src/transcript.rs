@@ -0,0 +1,227 @@
+//! The SMTP conversation with the relay, shown at the highest verbosity (`-vv`).  
+//! lettre reports every line it writes and reads through `tracing`, this forwards them to `log::trace`.  
+//! Credentials never reach the log: AUTH payloads and the answers to 334 challenges are redacted,  
+//! and the message itself is only shown with its size.  
+
+use std::{cell::Cell, fmt};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+
+use crate::log::{self, Level};
+
+/// Commands that are safe to show as they are, everything else the client writes is redacted
+const COMMANDS: [&str; 12] = [
+    "EHLO", "HELO", "STARTTLS", "MAIL", "RCPT", "DATA", "RSET", "NOOP", "QUIT", "VRFY", "EXPN", "HELP",
+];
+
+/// What the relay asked for in its last reply
+#[derive(Clone, Copy)]
+enum Expecting {
+    Command,
+    /// After `334`, the next line is part of the login
+    Challenge,
+    /// After `354`, the next write is the message
+    Message,
+}
+
+thread_local! {
+    /// Kept per thread, as lettre's blocking transport has each conversation on a thread of its own.
+    /// With the `async` feature a conversation can move between threads, and a line may be read with the state
+    /// of another one: the answer to a challenge is then still redacted like any unknown line,
+    /// but a message may be shown as `[redacted]` instead of with its size.
+    static EXPECTING: Cell<Expecting> = const { Cell::new(Expecting::Command) };
+}
+
+struct Transcript;
+
+/// Forward lettre's SMTP conversation to the log, if `-vv` was passed
+pub fn init() {
+    if log::enabled(Level::Trace) {
+        let _ = tracing::subscriber::set_global_default(Transcript);
+    }
+}
+
+impl Transcript {
+    /// A line written by lettre, with its `\r\n`s shown as `<CRLF>`
+    fn wrote(&self, written: &str) -> String {
+        let line = written.strip_suffix("<CRLF>").unwrap_or(written);
+        let expecting = EXPECTING.replace(Expecting::Command);
+
+        // The message comes first: its first line may well start with a command, e.g. "DATA"
+        let verb = line.split(' ').next().unwrap_or_default();
+        let shown = match expecting {
+            _ if line == "<CRLF>." => ".".to_string(),
+            Expecting::Challenge => "[redacted]".to_string(),
+            Expecting::Message => format!("[message, {} bytes]", written.replace("<CRLF>", "\r\n").len()),
+            // `AUTH PLAIN <payload>` or `AUTH LOGIN`
+            Expecting::Command if verb == "AUTH" => match line.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                [_, mechanism, _] => format!("AUTH {mechanism} [redacted]"),
+                _ => line.to_string(),
+            },
+            Expecting::Command if COMMANDS.contains(&verb) => line.to_string(),
+            Expecting::Command => "[redacted]".to_string(),
+        };
+
+        format!("> {shown}")
+    }
+
+    /// The reply read by lettre so far, a multiline reply is logged again with every line
+    fn read(&self, reply: &str) -> String {
+        let reply = reply.strip_suffix("<CRLF>").unwrap_or(reply);
+        let line = reply.rsplit("<CRLF>").next().unwrap_or(reply);
+        EXPECTING.set(match line.get(..3) {
+            Some("334") => Expecting::Challenge,
+            Some("354") => Expecting::Message,
+            _ => Expecting::Command,
+        });
+
+        format!("< {line}")
+    }
+}
+
+impl Subscriber for Transcript {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // The AUTH commands log the decoded challenges, which are left out with the rest of the login
+        metadata.is_event()
+            && metadata.target().starts_with("lettre::transport::smtp")
+            && !metadata.target().ends_with("commands")
+    }
+
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let message = message.0;
+
+        log::trace(if let Some(line) = message.strip_prefix("Wrote: ") {
+            self.wrote(line)
+        } else if let Some(line) = message.strip_prefix("<< ") {
+            self.read(line)
+        } else {
+            message
+        });
+    }
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
+
+/// The formatted `message` field of an event
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_plain_is_redacted() {
+        let transcript = Transcript;
+        // "\0alice\0secret"
+        let shown = transcript.wrote("AUTH PLAIN AGFsaWNlAHNlY3JldA==<CRLF>");
+
+        assert_eq!(shown, "> AUTH PLAIN [redacted]");
+    }
+
+    #[test]
+    fn auth_login_answers_are_redacted() {
+        let transcript = Transcript;
+        let mut shown = vec![transcript.wrote("AUTH LOGIN<CRLF>")];
+        shown.push(transcript.read("334 VXNlcm5hbWU6<CRLF>"));
+        // "alice"
+        shown.push(transcript.wrote("YWxpY2U=<CRLF>"));
+        shown.push(transcript.read("334 UGFzc3dvcmQ6<CRLF>"));
+        // "secret"
+        shown.push(transcript.wrote("c2VjcmV0<CRLF>"));
+        shown.push(transcript.read("235 2.7.0 Authentication successful<CRLF>"));
+
+        assert_eq!(
+            shown,
+            [
+                "> AUTH LOGIN",
+                "< 334 VXNlcm5hbWU6",
+                "> [redacted]",
+                "< 334 UGFzc3dvcmQ6",
+                "> [redacted]",
+                "< 235 2.7.0 Authentication successful",
+            ]
+        );
+    }
+
+    #[test]
+    fn message_is_shown_with_its_size() {
+        let transcript = Transcript;
+        let mut shown = vec![transcript.wrote("DATA<CRLF>")];
+        shown.push(transcript.read("354 End data with <CR><LF>.<CR><LF><CRLF>"));
+        shown.push(transcript.wrote("Subject: login<CRLF><CRLF>the password is secret<CRLF>"));
+        shown.push(transcript.wrote("<CRLF>.<CRLF>"));
+        shown.push(transcript.read("250 2.0.0 Ok<CRLF>"));
+        shown.push(transcript.wrote("QUIT<CRLF>"));
+
+        assert_eq!(
+            shown,
+            [
+                "> DATA",
+                "< 354 End data with <CR><LF>.<CR><LF>",
+                "> [message, 42 bytes]",
+                "> .",
+                "< 250 2.0.0 Ok",
+                "> QUIT",
+            ]
+        );
+    }
+
+    #[test]
+    fn message_starting_with_a_command_is_not_shown() {
+        let transcript = Transcript;
+        let mut shown = Vec::new();
+
+        for first_line in ["DATA", "AUTH PLAIN AGFsaWNlAHNlY3JldA==", "MAIL FROM:<a@example.com>"] {
+            shown.push(transcript.wrote("DATA<CRLF>"));
+            shown.push(transcript.read("354 End data with <CR><LF>.<CR><LF><CRLF>"));
+            shown.push(transcript.wrote(&format!("{first_line}<CRLF>secret<CRLF>")));
+            shown.push(transcript.wrote("<CRLF>.<CRLF>"));
+            shown.push(transcript.read("250 2.0.0 Ok<CRLF>"));
+        }
+
+        assert_eq!(
+            shown,
+            [
+                "> DATA",
+                "< 354 End data with <CR><LF>.<CR><LF>",
+                "> [message, 14 bytes]",
+                "> .",
+                "< 250 2.0.0 Ok",
+                "> DATA",
+                "< 354 End data with <CR><LF>.<CR><LF>",
+                "> [message, 41 bytes]",
+                "> .",
+                "< 250 2.0.0 Ok",
+                "> DATA",
+                "< 354 End data with <CR><LF>.<CR><LF>",
+                "> [message, 35 bytes]",
+                "> .",
+                "< 250 2.0.0 Ok",
+            ]
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! `mailr watch`: follow a log file and mail digests of the lines matching a pattern.  
+//! After the first match, lines are collected for `--window`, and at most one digest goes out per `--interval`;  
+//! matches in between wait for the next digest. Rotated (moved or recreated) and truncated files are followed.  
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use regex::Regex;
+
+use crate::{config::ConfigManager, info, run, schedule, send_or_queue, templates, warning, Args};
+
+/// How often the file is checked for new lines
+const POLL: Duration = Duration::from_secs(1);
+
+const DEFAULT_SUBJECT: &str = "[watch] {{ count }} matches in {{ file }} on {{ host }}";
+
+/// Variables available in `--subject`
+const SUBJECT_VARS: [&str; 3] = ["count", "file", "host"];
+
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// The log file to follow
+    pub file: PathBuf,
+    #[arg(short, long, required = true, value_name("REGEX"), help("mail lines matching this pattern, can be repeated"))]
+    pub pattern: Vec<String>,
+    #[arg(short, long, help("where to send the digests"))]
+    pub to: String,
+    #[arg(long, value_name("DURATION"), value_parser(schedule::parse_delay), default_value = "1m", help("how long to collect matches before sending them"))]
+    pub window: Duration,
+    #[arg(long, value_name("DURATION"), value_parser(schedule::parse_delay), default_value = "10m", help("minimum time between two digests"))]
+    pub interval: Duration,
+    #[arg(short, long, default_value = DEFAULT_SUBJECT, help("subject template, with {{count}}, {{file}} and {{host}}"))]
+    pub subject: String,
+    #[arg(short, long, value_name("IDENTITY"), help("send as one of the configured identities instead of the default"))]
+    pub from: Option<String>,
+    #[arg(long, default_value_t = 500, help("most lines in one digest, the rest is only counted"))]
+    pub max_lines: usize,
+    #[arg(long, action, help("also check the lines already in the file, instead of only new ones"))]
+    pub from_start: bool,
+}
+
+/// Identifies the file behind a path, so a rotation can be noticed
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Windows has no stable file index in std, so the creation time has to do.
+/// It can survive a file being recreated within a few seconds (file tunneling),
+/// which is why `Follower::lines` also treats a file that got shorter as rotated.
+#[cfg(not(unix))]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    let created = metadata.created().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some((created.as_secs(), created.subsec_nanos().into()))
+}
+
+/// Reads the lines appended to a file, like `tail -F`
+struct Follower {
+    path: PathBuf,
+    file: Option<File>,
+    id: Option<(u64, u64)>,
+    pos: u64,
+    /// The start of a line that hasn't been finished yet
+    partial: Vec<u8>,
+}
+
+impl Follower {
+    fn new(path: &Path, from_start: bool) -> anyhow::Result<Self> {
+        let mut follower = Self {
+            path: path.to_path_buf(),
+            file: None,
+            id: None,
+            pos: 0,
+            partial: Vec::new(),
+        };
+
+        match File::open(path) {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                follower.id = file_id(&metadata);
+                follower.pos = if from_start { 0 } else { metadata.len() };
+                follower.file = Some(file);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info(format!("'{}' doesn't exist yet, waiting for it", path.display()));
+            }
+            Err(err) => anyhow::bail!("failed to open '{}': {err}", path.display()),
+        }
+
+        Ok(follower)
+    }
+
+    /// Read everything after `pos` in the current file
+    fn read_new(&mut self, lines: &mut Vec<String>) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+
+        // Truncated in place (copytruncate), start over
+        if file.metadata()?.len() < self.pos {
+            self.pos = 0;
+            self.partial.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.pos))?;
+        let mut data = Vec::new();
+        self.pos += file.read_to_end(&mut data)? as u64;
+
+        self.partial.extend(data);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\n', '\r']).to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The complete lines written since the last call
+    fn lines(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+
+        // Whatever was written to the old file before the rotation still counts
+        self.read_new(&mut lines)?;
+
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // Moved away, the new file isn't there yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(lines),
+            Err(err) => return Err(err),
+        };
+
+        // A truncated file was already handled by `read_new`, so a shorter one is a new file
+        let rotated = match (&self.file, file_id(&metadata)) {
+            (None, _) => true,
+            (Some(_), Some(id)) => Some(id) != self.id || metadata.len() < self.pos,
+            (Some(_), None) => metadata.len() < self.pos,
+        };
+        if rotated {
+            info(format!("following the new '{}'", self.path.display()));
+            let file = File::open(&self.path)?;
+            self.id = file_id(&file.metadata()?);
+            self.file = Some(file);
+            self.pos = 0;
+            self.partial.clear();
+            self.read_new(&mut lines)?;
+        }
+
+        Ok(lines)
+    }
+}
+
+/// Matching lines that haven't been sent yet
+struct Digest {
+    lines: Vec<String>,
+    /// All matches, including those beyond `--max-lines`
+    count: usize,
+    first: DateTime<Local>,
+    started: Instant,
+}
+
+fn send_digest(cf: &ConfigManager, args: &WatchArgs, digest: &Digest) -> anyhow::Result<()> {
+    let host = run::hostname();
+    let file = args.file.display().to_string();
+
+    let vars = BTreeMap::from([
+        ("count", digest.count.to_string()),
+        ("file", file.clone()),
+        ("host", host.clone()),
+    ]);
+
+    let mut msg = format!(
+        "{} lines of '{file}' on {host} matched {} between {} and {}:\n\n",
+        digest.count,
+        args.pattern.join(" or "),
+        digest.first.format("%Y-%m-%d %H:%M:%S"),
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+    );
+    for line in &digest.lines {
+        msg.push_str(line);
+        msg.push('\n');
+    }
+    if digest.count > digest.lines.len() {
+        msg.push_str(&format!("\n({} more matching lines not shown)\n", digest.count - digest.lines.len()));
+    }
+
+    let mail = Args {
+        from: args.from.clone(),
+        to: args.to.clone(),
+        subject: templates::render_subject(&args.subject, &vars)?,
+        msg,
+        no_signature: true,
+        ..Default::default()
+    };
+
+    send_or_queue(cf, &mail)
+}
+
+/// `mailr watch`, runs until it is stopped
+pub fn watch(cf: &ConfigManager, args: &WatchArgs) -> anyhow::Result<()> {
+    let patterns = args
+        .pattern
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(|e| anyhow::anyhow!("invalid pattern '{pattern}': {e}")))
+        .collect::<anyhow::Result<Vec<Regex>>>()?;
+    templates::check_subject(&args.subject, &SUBJECT_VARS)?;
+
+    let mut follower = Follower::new(&args.file, args.from_start)?;
+    let mut digest: Option<Digest> = None;
+    let mut last_sent: Option<Instant> = None;
+
+    info(format!("watching '{}', press CTRL-C to stop", args.file.display()));
+
+    loop {
+        let lines = follower
+            .lines()
+            .map_err(|e| anyhow::anyhow!("failed to read '{}': {e}", args.file.display()))?;
+
+        for line in lines {
+            if !patterns.iter().any(|pattern| pattern.is_match(&line)) {
+                continue;
+            }
+
+            let digest = digest.get_or_insert_with(|| Digest {
+                lines: Vec::new(),
+                count: 0,
+                first: Local::now(),
+                started: Instant::now(),
+            });
+            digest.count += 1;
+            if digest.lines.len() < args.max_lines {
+                digest.lines.push(line);
+            }
+        }
+
+        let due = digest.as_ref().is_some_and(|digest| digest.started.elapsed() >= args.window)
+            && last_sent.is_none_or(|sent| sent.elapsed() >= args.interval);
+
+        if due {
+            if let Some(digest) = digest.take() {
+                // A failed digest is dropped, retrying it every second would flood the relay
+                if let Err(err) = send_digest(cf, args, &digest) {
+                    warning(format!("failed to send {} matches: {err}", digest.count));
+                }
+                last_sent = Some(Instant::now());
+            }
+        }
+
+        thread::sleep(POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::OpenOptions, io::Write, process};
+
+    use super::*;
+
+    /// A path in a fresh directory, the file itself doesn't exist yet
+    fn log_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mailr-test-{}-watch-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("app.log")
+    }
+
+    fn append(path: &Path, data: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn only_lines_written_after_the_start_are_read() {
+        let path = log_path("start");
+        append(&path, "old\n");
+
+        let mut follower = Follower::new(&path, false).unwrap();
+        assert!(follower.lines().unwrap().is_empty());
+
+        append(&path, "new\r\n");
+        assert_eq!(follower.lines().unwrap(), ["new"]);
+        assert_eq!(Follower::new(&path, true).unwrap().lines().unwrap(), ["old", "new"]);
+    }
+
+    #[test]
+    fn partial_lines_wait_for_their_end() {
+        let path = log_path("partial");
+        let mut follower = Follower::new(&path, true).unwrap();
+
+        append(&path, "first\nsec");
+        assert_eq!(follower.lines().unwrap(), ["first"]);
+
+        append(&path, "ond\nthi");
+        assert_eq!(follower.lines().unwrap(), ["second"]);
+    }
+
+    #[test]
+    fn files_moved_away_are_followed_to_the_new_file() {
+        let path = log_path("rename");
+        append(&path, "one\n");
+        let mut follower = Follower::new(&path, true).unwrap();
+        assert_eq!(follower.lines().unwrap(), ["one"]);
+
+        // The last lines of the old file still count
+        append(&path, "two\n");
+        fs::rename(&path, path.with_extension("log.1")).unwrap();
+        assert_eq!(follower.lines().unwrap(), ["two"]);
+
+        append(&path, "three\n");
+        assert_eq!(follower.lines().unwrap(), ["three"]);
+    }
+
+    #[test]
+    fn files_truncated_in_place_are_read_from_the_start() {
+        let path = log_path("truncate");
+        append(&path, "a long first line\n");
+        let mut follower = Follower::new(&path, true).unwrap();
+        assert_eq!(follower.lines().unwrap(), ["a long first line"]);
+
+        OpenOptions::new().write(true).truncate(true).open(&path).unwrap();
+        append(&path, "short\n");
+        assert_eq!(follower.lines().unwrap(), ["short"]);
+    }
+
+    #[test]
+    fn files_created_after_the_start_are_read_from_the_start() {
+        let path = log_path("created");
+        let mut follower = Follower::new(&path, false).unwrap();
+        assert!(follower.lines().unwrap().is_empty());
+
+        append(&path, "hello\n");
+        assert_eq!(follower.lines().unwrap(), ["hello"]);
+    }
+}